@@ -1,20 +1,53 @@
+## comments start with ## and can also go after a statement
+## colors and strings can be named once and used as $name anywhere one is expected
+let black = #000
+
+## other files can be pulled in too, relative paths start from the file doing the including
+## ~ and $ENV_VARS work, and changes to included files are reloaded like this one
+## include "~/.config/bspwm-workspace-icons/colors.i"
+
 before_fmt "(( "
 
-## available fmt values: desktop, focused, occupied, reversed, icon, window_class
+## available fmt values: desktop, focused, occupied, urgent, reversed, icon, icons, window_class
+## {icon} is the icon of the last focused window, {icons} has the icons of every window on the desktop
+## named regex groups in rules can be used too, title "(?P<project>\\w+) - VSCodium" gives {project}
 ## just like in rust, curly braces are escaped with another curly brace
 fmt "{desktop} {focused} {occupied} {reversed} {icon} {color};  "
 
+## put around every icon in {icons}
+## before_icon ""
+## after_icon " "
+
 after_fmt ")) "
 
+## rules are tried top to bottom, the first one that matches wins
+## class and instance are the two halves of WM_CLASS, role is WM_WINDOW_ROLE,
+## type is _NET_WM_WINDOW_TYPE in lowercase without the prefix, like "dialog" or "normal"
+## process matches the name or command line of the program that owns the window (from _NET_WM_PID),
+## child_process the same for anything it started, like an editor running in a terminal
+## type "dialog" ""
+## class "kitty" and child_process "nvim.*" ""
+## instance "crx_.*" ""
+## rules can be combined with and, or and not, not goes first, then and, then or
+## title ".*GitHub.*" and not class "kitty" ""
+## a statement can go on over the next line after a trailing \, and the icon with its colors can be put in { }
+## class "code" \
+##     or class "VSCodium" {
+##         "" fallback "code"
+##         color #07c
+##     }
+
+## icons can be any glyph (or several), or quoted text like "FF"
+## fallback "..." is shown instead when running with --fallback-icons
 title ".*Reddit.*"   
 title ".*Stack Overflow.*" 
 title ".*YouTube.*"   focused_color #890
-class "firefox"    color #42069 focused_color #2137
-class "discord" 
+class "firefox"    color #420690 focused_color #2137
+class "discord"   urgent_color #e33
 class "steam" 
 class "kitty"   color #500 reversed
 class "mpv"  reversed
 class "steam_app*" 󰊗
 
-empty     color #000 focused_color #000
-default   color #000 focused_color #000
+empty     color $black focused_color $black
+default   color $black focused_color $black
@@ -0,0 +1,450 @@
+use xcb::x;
+use xcb::{Xid, XidNew};
+use std::collections::HashMap;
+use crate::backend::{Backend, WmEvent};
+use crate::window::{Atoms, KnownWindow, watch_window};
+
+fn get_cardinals(x_conn: &xcb::Connection, window: x::Window, property: x::Atom) -> Vec<u32> {
+    let cookie = x_conn.send_request(&x::GetProperty {
+        delete: false,
+        window,
+        property,
+        r#type: x::ATOM_ANY,
+        long_offset: 0,
+        long_length: 4096
+    });
+
+    match x_conn.wait_for_reply(cookie) {
+        Ok(r) if r.format() == 32 => r.value::<u32>().to_vec(),
+        _ => vec![]
+    }
+}
+
+fn get_strings(x_conn: &xcb::Connection, window: x::Window, property: x::Atom) -> Vec<String> {
+    let cookie = x_conn.send_request(&x::GetProperty {
+        delete: false,
+        window,
+        property,
+        r#type: x::ATOM_ANY,
+        long_offset: 0,
+        long_length: 4096
+    });
+
+    match x_conn.wait_for_reply(cookie) {
+        Ok(r) if r.format() == 8 => String::from_utf8_lossy(r.value::<u8>())
+            .split('\0')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect(),
+        _ => vec![]
+    }
+}
+
+pub struct EwmhBackend {
+    x_conn: xcb::Connection,
+    atoms: Atoms,
+    root: x::Window,
+    workspaces: Vec<String>,
+    desktops: Vec<(u32, String)>, // id and name in ewmh order, ewmh only has the index so ids are ours
+    next_id: u32,
+    current: Option<u32>,
+    windows: HashMap<u32, u32>, // window id -> desktop id, only for the desktops we track
+    initial: bool
+}
+
+impl EwmhBackend {
+    pub fn new(workspaces: Vec<String>) -> xcb::Result<Self> {
+        Self::connect(None, workspaces)
+    }
+
+    // `display` is whatever $DISPLAY would be, None to use $DISPLAY
+    fn connect(display: Option<&str>, workspaces: Vec<String>) -> xcb::Result<Self> {
+        let (x_conn, screen_num) = xcb::Connection::connect(display)?;
+        let setup = x_conn.get_setup();
+        let root = setup.roots().nth(screen_num as usize).unwrap().root();
+        let atoms = Atoms::intern_all(&x_conn)?;
+
+        watch_window(&x_conn, root.resource_id());
+        x_conn.flush()?;
+
+        Ok(Self { x_conn, atoms, root, workspaces, desktops: vec![], next_id: 1, current: None, windows: HashMap::new(), initial: true })
+    }
+
+    fn read_desktops(&mut self, events: &mut Vec<WmEvent>) {
+        let count = get_cardinals(&self.x_conn, self.root, self.atoms.number_of_desktops).first().copied().unwrap_or(0) as usize;
+        let names = desktop_names(count, get_strings(&self.x_conn, self.root, self.atoms.desktop_names));
+
+        self.desktops = diff_desktops(&self.desktops, &names, &mut self.next_id, &self.workspaces, &mut self.windows, events);
+    }
+
+    fn read_current(&mut self, events: &mut Vec<WmEvent>) {
+        let current = get_cardinals(&self.x_conn, self.root, self.atoms.current_desktop).first().copied().unwrap_or(0);
+        let id = self.desktops.get(current as usize).filter(|(_, n)| tracked(&self.workspaces, n)).map(|(id, _)| *id);
+        if let Some(focused) = id.filter(|_| id != self.current) {
+            events.push(WmEvent::DesktopFocused(focused));
+            self.current = id;
+        }
+    }
+
+    fn read_clients(&mut self, events: &mut Vec<WmEvent>) {
+        let clients :Vec<(u32, u32)> = get_cardinals(&self.x_conn, self.root, self.atoms.client_list).into_iter()
+            .filter_map(|id| {
+                watch_window(&self.x_conn, id);
+                let client = unsafe { x::Window::new(id) };
+                get_cardinals(&self.x_conn, client, self.atoms.wm_desktop).first().map(|d| (id, *d))
+            })
+            .collect();
+
+        let (x_conn, atoms) = (&self.x_conn, &self.atoms);
+        self.windows = diff_clients(&clients, &self.desktops, &self.workspaces, &self.windows, |id| KnownWindow::new(x_conn, atoms, &id), events);
+    }
+}
+
+fn tracked(workspaces: &[String], name: &str) -> bool {
+    workspaces.is_empty() || workspaces.iter().any(|w| w == name)
+}
+
+// not every wm names its desktops, so fall back to the index
+fn desktop_names(count: usize, mut names: Vec<String>) -> Vec<String> {
+    names.truncate(count);
+    for i in names.len()..count {
+        names.push(format!("{}", i + 1));
+    }
+
+    names
+}
+
+// desktops only have an index in ewmh, so one keeps its id as long as its name is still around,
+// a name that's gone with a new one in its place is a rename and anything else that changed was removed or added,
+// windows on a desktop that's gone are forgotten
+fn diff_desktops(
+    old: &[(u32, String)], new: &[String], next_id: &mut u32, workspaces: &[String],
+    windows: &mut HashMap<u32, u32>, events: &mut Vec<WmEvent>
+) -> Vec<(u32, String)> {
+    // the same name twice pairs up in order
+    let mut kept = vec![false; old.len()];
+    let mut ids :Vec<Option<u32>> = new.iter().map(|n| {
+        let i = (0..old.len()).find(|i| !kept[*i] && old[*i].1 == *n)?;
+        kept[i] = true;
+        Some(old[i].0)
+    }).collect();
+
+    let mut renamed = vec![];
+    for (i, n) in new.iter().enumerate() {
+        if ids[i].is_some() || old.iter().any(|(_, o)| o == n) || kept.get(i) != Some(&false) { continue; }
+        kept[i] = true;
+        ids[i] = Some(old[i].0);
+        renamed.push((old[i].0, &old[i].1, n));
+    }
+
+    for (i, (id, o)) in old.iter().enumerate() {
+        if !kept[i] && tracked(workspaces, o) { events.push(WmEvent::DesktopRemoved(*id)); }
+    }
+
+    let mut desktops = vec![];
+    for (n, id) in new.iter().zip(ids) {
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let id = *next_id;
+                *next_id += 1;
+                if tracked(workspaces, n) { events.push(WmEvent::DesktopAdded { id, name: n.clone() }); }
+                id
+            }
+        };
+
+        // a rename can take a desktop in or out of the ones we track
+        if let Some((_, o, _)) = renamed.iter().find(|(r, _, _)| *r == id) {
+            match (tracked(workspaces, o), tracked(workspaces, n)) {
+                (true, true) => events.push(WmEvent::DesktopRenamed { id, name: n.clone() }),
+                (true, false) => events.push(WmEvent::DesktopRemoved(id)),
+                (false, true) => events.push(WmEvent::DesktopAdded { id, name: n.clone() }),
+                (false, false) => ()
+            }
+        }
+
+        desktops.push((id, n.clone()));
+    }
+
+    windows.retain(|_, desktop| desktops.iter().any(|(id, n)| id == desktop && tracked(workspaces, n)));
+    desktops
+}
+
+// `clients` is every window with the index of its desktop, what comes back is the ones on desktops we track,
+// `new_window` reads whatever we need to know about a window we haven't seen before
+fn diff_clients(
+    clients: &[(u32, u32)], desktops: &[(u32, String)], workspaces: &[String], windows: &HashMap<u32, u32>,
+    new_window: impl Fn(u32) -> KnownWindow, events: &mut Vec<WmEvent>
+) -> HashMap<u32, u32> {
+    let mut seen :HashMap<u32, u32> = HashMap::new();
+
+    for (id, desktop) in clients.iter().copied() {
+        // sticky windows have 0xFFFFFFFF here
+        if let Some((desktop, _)) = desktops.get(desktop as usize).filter(|(_, n)| tracked(workspaces, n)) {
+            seen.insert(id, *desktop);
+            match windows.get(&id) {
+                Some(old) if old == desktop => (),
+                Some(_) => events.push(WmEvent::WindowMoved { id, desktop: *desktop }),
+                None => events.push(WmEvent::WindowAdded { desktop: *desktop, window: new_window(id) }),
+            }
+        }
+    }
+
+    for id in windows.keys() {
+        if !seen.contains_key(id) { events.push(WmEvent::WindowRemoved(*id)); }
+    }
+
+    seen
+}
+
+impl Backend for EwmhBackend {
+    fn poll(&mut self) -> xcb::Result<Option<Vec<WmEvent>>> {
+        let mut events = vec![];
+
+        if self.initial {
+            self.initial = false;
+            self.read_desktops(&mut events);
+            self.read_clients(&mut events);
+            self.read_current(&mut events);
+            self.x_conn.flush()?;
+            return Ok(Some(events));
+        }
+
+        let event = match self.x_conn.wait_for_event() {
+            Ok(xcb::Event::X(x::Event::PropertyNotify(ev))) => ev,
+            Ok(_) => return Ok(Some(events)),
+            Err(xcb::Error::Protocol(_)) => return Ok(Some(events)), // windows can disappear between the client list and us asking about them
+            Err(e) => return Err(e)
+        };
+
+        let atom = event.atom();
+        if event.window() == self.root {
+            if atom == self.atoms.current_desktop {
+                self.read_current(&mut events);
+            } else if atom == self.atoms.active_window {
+                if let Some(id) = get_cardinals(&self.x_conn, self.root, self.atoms.active_window).first() {
+                    if self.windows.contains_key(id) { events.push(WmEvent::WindowFocused(*id)); }
+                }
+            } else if atom == self.atoms.number_of_desktops || atom == self.atoms.desktop_names {
+                self.read_desktops(&mut events);
+                self.read_clients(&mut events);
+                self.read_current(&mut events);
+            } else if atom == self.atoms.client_list {
+                self.read_clients(&mut events);
+            }
+        } else if atom == self.atoms.wm_desktop {
+            self.read_clients(&mut events);
+        } else if self.atoms.is_window_property(atom) && self.windows.contains_key(&event.window().resource_id()) {
+            events.push(WmEvent::WindowRetitled(KnownWindow::new(&self.x_conn, &self.atoms, &event.window().resource_id())));
+        }
+
+        self.x_conn.flush()?;
+        Ok(Some(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Workspaces;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn summary(events: &[WmEvent]) -> Vec<String> {
+        events.iter().map(|e| match e {
+            WmEvent::WindowAdded { desktop, window } => format!("added {} {desktop}", window.id),
+            e => format!("{e:?}")
+        }).collect()
+    }
+
+    #[test]
+    fn unnamed_desktops_get_their_number() {
+        assert_eq!(desktop_names(3, names(&["web"])), ["web", "2", "3"]);
+        assert_eq!(desktop_names(1, names(&["web", "code"])), ["web"]);
+    }
+
+    // ids from 1 in order, like the backend hands them out
+    fn numbered(names: &[&str]) -> Vec<(u32, String)> {
+        names.iter().enumerate().map(|(i, n)| (i as u32 + 1, n.to_string())).collect()
+    }
+
+    fn diff(old: &[(u32, String)], new: &[&str], workspaces: &[&str], windows: &mut HashMap<u32, u32>) -> (Vec<(u32, String)>, Vec<String>) {
+        let mut next_id = old.len() as u32 + 1;
+        let mut events = vec![];
+        let desktops = diff_desktops(old, &names(new), &mut next_id, &names(workspaces), windows, &mut events);
+        (desktops, summary(&events))
+    }
+
+    #[test]
+    fn windows_go_with_a_renamed_desktop() {
+        let mut windows = HashMap::from([(10, 1), (11, 2), (12, 3)]);
+        let (desktops, events) = diff(&numbered(&["web", "code", "chat"]), &["www", "code"], &[], &mut windows);
+
+        assert_eq!(events, ["DesktopRemoved(3)", "DesktopRenamed { id: 1, name: \"www\" }"]);
+        assert_eq!(desktops, numbered(&["www", "code"]));
+        assert_eq!(windows, HashMap::from([(10, 1), (11, 2)]));
+    }
+
+    #[test]
+    fn removing_a_desktop_in_the_middle_keeps_the_rest() {
+        let mut windows = HashMap::from([(1, 1), (2, 2), (3, 3)]);
+        let (desktops, events) = diff(&numbered(&["a", "b", "c"]), &["a", "c"], &[], &mut windows);
+
+        assert_eq!(events, ["DesktopRemoved(2)"]);
+        assert_eq!(desktops, [(1, "a".to_string()), (3, "c".to_string())]);
+        assert_eq!(windows, HashMap::from([(1, 1), (3, 3)]));
+
+        // and what the bar makes of it
+        let mut workspaces = Workspaces::new();
+        for (id, name) in numbered(&["a", "b", "c"]) { workspaces.apply(WmEvent::DesktopAdded { id, name }); }
+        workspaces.apply(WmEvent::WindowAdded { desktop: 3, window: KnownWindow::new_known(3, String::new(), String::new()) });
+        workspaces.apply(WmEvent::DesktopRemoved(2));
+
+        let shown :Vec<(&str, usize)> = workspaces.desktops.iter().map(|d| (d.name.as_str(), d.windows.len())).collect();
+        assert_eq!(shown, [("a", 0), ("c", 1)]);
+    }
+
+    #[test]
+    fn a_new_name_that_was_already_there_is_not_a_rename() {
+        let mut windows = HashMap::from([(1, 1), (2, 3)]);
+        let (desktops, events) = diff(&numbered(&["a", "b", "c"]), &["c", "a", "d"], &[], &mut windows);
+
+        assert_eq!(events, ["DesktopRemoved(2)", "DesktopAdded { id: 4, name: \"d\" }"]);
+        assert_eq!(desktops, [(3, "c".to_string()), (1, "a".to_string()), (4, "d".to_string())]);
+        assert_eq!(windows, HashMap::from([(1, 1), (2, 3)]));
+    }
+
+    #[test]
+    fn the_same_name_twice_is_two_desktops() {
+        let (desktops, events) = diff(&numbered(&["Desktop"]), &["Desktop", "Desktop"], &[], &mut HashMap::new());
+        assert_eq!(events, ["DesktopAdded { id: 2, name: \"Desktop\" }"]);
+        assert_eq!(desktops, numbered(&["Desktop", "Desktop"]));
+
+        let mut windows = HashMap::from([(1, 1), (2, 2)]);
+        let (_, events) = diff(&desktops, &["Desktop"], &[], &mut windows);
+        assert_eq!(events, ["DesktopRemoved(2)"]);
+        assert_eq!(windows, HashMap::from([(1, 1)]));
+    }
+
+    #[test]
+    fn only_tracked_desktops_count() {
+        let mut windows = HashMap::from([(1, 1)]);
+
+        // renaming one we don't track to one we do is it showing up
+        let (desktops, events) = diff(&numbered(&["web", "code"]), &["www", "chat", "mail"], &["web", "www", "chat"], &mut windows);
+
+        assert_eq!(events, ["DesktopRenamed { id: 1, name: \"www\" }", "DesktopAdded { id: 2, name: \"chat\" }"]);
+        assert_eq!(desktops, numbered(&["www", "chat", "mail"]));
+        assert_eq!(windows, HashMap::from([(1, 1)]));
+    }
+
+    #[test]
+    fn clients_are_added_moved_and_removed() {
+        let desktops = numbered(&["web", "code", "chat"]);
+        let workspaces = names(&["web", "code"]);
+        let windows = HashMap::from([(1, 1), (2, 1), (3, 2)]);
+        let mut events = vec![];
+
+        // 1 stays, 2 moves, 3 is gone, 4 is new, 5 is sticky and 6 is on a desktop we don't track
+        let clients = [(1, 0), (2, 1), (4, 1), (5, 0xFFFFFFFF), (6, 2)];
+        let seen = diff_clients(&clients, &desktops, &workspaces, &windows, |id| KnownWindow::new_known(id, String::new(), String::new()), &mut events);
+
+        assert_eq!(summary(&events), ["WindowMoved { id: 2, desktop: 2 }", "added 4 2", "WindowRemoved(3)"]);
+        assert_eq!(seen, HashMap::from([(1, 1), (2, 2), (4, 2)]));
+    }
+
+    // a throwaway X server, killed when the test is done with it
+    struct Xvfb(std::process::Child);
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn set_cardinals(x_conn: &xcb::Connection, window: x::Window, property: x::Atom, r#type: x::Atom, values: &[u32]) {
+        x_conn.send_request(&x::ChangeProperty { mode: x::PropMode::Replace, window, property, r#type, data: values });
+    }
+
+    fn set_string(x_conn: &xcb::Connection, window: x::Window, property: x::Atom, r#type: x::Atom, value: &str) {
+        x_conn.send_request(&x::ChangeProperty { mode: x::PropMode::Replace, window, property, r#type, data: value.as_bytes() });
+    }
+
+    fn set_desktops(x_conn: &xcb::Connection, root: x::Window, atoms: &Atoms, names: &[&str]) {
+        set_cardinals(x_conn, root, atoms.number_of_desktops, x::ATOM_CARDINAL, &[names.len() as u32]);
+        set_string(x_conn, root, atoms.desktop_names, atoms.utf8_string, &(names.join("\0") + "\0"));
+    }
+
+    fn add_client(x_conn: &xcb::Connection, root: x::Window, atoms: &Atoms, desktop: u32, class: &str) -> x::Window {
+        let window :x::Window = x_conn.generate_id();
+        x_conn.send_request(&x::CreateWindow {
+            depth: x::COPY_FROM_PARENT as u8,
+            wid: window,
+            parent: root,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            border_width: 0,
+            class: x::WindowClass::InputOutput,
+            visual: x::COPY_FROM_PARENT,
+            value_list: &[]
+        });
+
+        set_string(x_conn, window, x::ATOM_WM_CLASS, x::ATOM_STRING, &format!("{}\0{class}\0", class.to_lowercase()));
+        set_string(x_conn, window, atoms.wm_name, atoms.utf8_string, class);
+        set_cardinals(x_conn, window, atoms.wm_desktop, x::ATOM_CARDINAL, &[desktop]);
+        window
+    }
+
+    // every batch the backend has for `count` property changes, which have to be done already
+    fn poll_changes(backend: &mut EwmhBackend, count: usize) -> Vec<String> {
+        (0..count).flat_map(|_| summary(&backend.poll().unwrap().unwrap())).collect()
+    }
+
+    #[test]
+    fn follows_the_root_window_properties() {
+        // goes through a real X server, so only where there's an Xvfb to start
+        let display = format!(":{}", 100 + std::process::id() % 10000);
+        let xvfb = std::process::Command::new("Xvfb").args([display.as_str(), "-nolisten", "tcp"])
+            .stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null()).spawn();
+        let _xvfb = match xvfb {
+            Ok(child) => Xvfb(child),
+            Err(_) => { eprintln!("no Xvfb, skipping"); return; }
+        };
+
+        let connected = (0..50).find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            xcb::Connection::connect(Some(&display)).ok()
+        });
+        let (x_conn, screen_num) = connected.expect("Xvfb didn't come up");
+        let root = x_conn.get_setup().roots().nth(screen_num as usize).unwrap().root();
+        let atoms = Atoms::intern_all(&x_conn).unwrap();
+
+        set_desktops(&x_conn, root, &atoms, &["a", "b", "c"]);
+        set_cardinals(&x_conn, root, atoms.current_desktop, x::ATOM_CARDINAL, &[1]);
+        let term = add_client(&x_conn, root, &atoms, 2, "Term");
+        set_cardinals(&x_conn, root, atoms.client_list, x::ATOM_WINDOW, &[term.resource_id()]);
+        x_conn.flush().unwrap();
+
+        let mut backend = EwmhBackend::connect(Some(&display), vec![]).unwrap();
+        assert_eq!(poll_changes(&mut backend, 1), [
+            "DesktopAdded { id: 1, name: \"a\" }", "DesktopAdded { id: 2, name: \"b\" }", "DesktopAdded { id: 3, name: \"c\" }",
+            &format!("added {} 3", term.resource_id()), "DesktopFocused(2)"
+        ]);
+
+        // the wm drops the focused desktop in the middle, the window moves down an index with its desktop
+        set_desktops(&x_conn, root, &atoms, &["a", "c"]);
+        set_cardinals(&x_conn, term, atoms.wm_desktop, x::ATOM_CARDINAL, &[1]);
+        x_conn.send_and_check_request(&x::NoOperation {}).unwrap();
+        assert_eq!(poll_changes(&mut backend, 3), ["DesktopRemoved(2)", "DesktopFocused(3)"]);
+
+        let web = add_client(&x_conn, root, &atoms, 0, "Web");
+        set_cardinals(&x_conn, root, atoms.client_list, x::ATOM_WINDOW, &[term.resource_id(), web.resource_id()]);
+        x_conn.send_and_check_request(&x::NoOperation {}).unwrap();
+        assert_eq!(poll_changes(&mut backend, 1), [format!("added {} 1", web.resource_id())]);
+    }
+}
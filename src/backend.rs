@@ -0,0 +1,316 @@
+use std::sync::{ Mutex, Arc, mpsc };
+use std::thread;
+use crate::icons::Icons;
+use crate::window::KnownWindow;
+use crate::output::Output;
+
+// desktops go by an id from the backend, names can repeat, bspwm names every new monitor's desktop "Desktop"
+#[derive(Debug)]
+pub enum WmEvent {
+    DesktopAdded { id: u32, name: String },
+    DesktopRemoved(u32),
+    DesktopFocused(u32),
+    DesktopRenamed { id: u32, name: String },
+    DesktopsSwapped(u32, u32),
+    DesktopTransferred { desktop: u32, monitor: String },
+    WindowAdded { desktop: u32, window: KnownWindow },
+    WindowFocused(u32),
+    WindowMoved { id: u32, desktop: u32 },
+    WindowRemoved(u32),
+    // the title (or anything else we read from X) changed, the window stays where it was
+    WindowRetitled(KnownWindow),
+    WindowUrgent { id: u32, urgent: bool },
+}
+
+pub trait Backend {
+    // blocks until the wm has something for us, an empty batch means nothing worth rendering happened
+    // None means the wm went away
+    fn poll(&mut self) -> xcb::Result<Option<Vec<WmEvent>>>;
+}
+
+#[derive(Debug)]
+pub struct Desktop {
+    pub id: u32, // whatever the backend knows it by, only the name gets shown
+    pub name: String,
+    pub monitor: String, // empty when the backend doesn't know about monitors
+    pub windows: Vec<KnownWindow>
+}
+
+impl Desktop {
+    pub fn is_urgent(&self) -> bool {
+        self.windows.iter().any(|w| w.urgent)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Workspaces {
+    pub desktops: Vec<Desktop>,
+    pub focused: Option<u32>
+}
+
+impl Workspaces {
+    pub fn new() -> Self {
+        Self { desktops: vec![], focused: None }
+    }
+
+    fn desktop_mut(&mut self, id: u32) -> Option<&mut Desktop> {
+        self.desktops.iter_mut().find(|d| d.id == id)
+    }
+
+    fn take_window(&mut self, id: u32) -> Option<KnownWindow> {
+        for desktop in &mut self.desktops {
+            if let Some(i) = desktop.windows.iter().position(|w| w.id == id) {
+                return Some(desktop.windows.remove(i));
+            }
+        }
+
+        None
+    }
+
+    pub fn apply(&mut self, event: WmEvent) {
+        match event {
+            WmEvent::DesktopAdded { id, name } => {
+                if self.desktop_mut(id).is_none() {
+                    self.desktops.push(Desktop { id, name, monitor: String::new(), windows: vec![] });
+                }
+            }
+
+            WmEvent::DesktopRemoved(id) => self.desktops.retain(|d| d.id != id),
+
+            WmEvent::DesktopFocused(id) => self.focused = Some(id),
+
+            WmEvent::DesktopRenamed { id, name } => {
+                if let Some(d) = self.desktop_mut(id) { d.name = name; }
+            }
+
+            // swapping across monitors moves each desktop to the other one's monitor too
+            WmEvent::DesktopsSwapped(a, b) => {
+                let a = self.desktops.iter().position(|d| d.id == a);
+                let b = self.desktops.iter().position(|d| d.id == b);
+                if let (Some(a), Some(b)) = (a, b) {
+                    self.desktops.swap(a, b);
+                    let monitor = std::mem::take(&mut self.desktops[a].monitor);
+                    self.desktops[a].monitor = std::mem::replace(&mut self.desktops[b].monitor, monitor);
+                }
+            }
+
+            WmEvent::DesktopTransferred { desktop, monitor } => {
+                if let Some(i) = self.desktops.iter().position(|d| d.id == desktop) {
+                    let old = std::mem::replace(&mut self.desktops[i].monitor, monitor);
+                    // bspwm puts transferred desktops last, the first time we hear about the monitor is not a transfer though
+                    if !old.is_empty() && old != self.desktops[i].monitor {
+                        let d = self.desktops.remove(i);
+                        self.desktops.push(d);
+                    }
+                }
+            }
+
+            WmEvent::WindowAdded { desktop, window } => {
+                self.take_window(window.id);
+                if let Some(d) = self.desktop_mut(desktop) { d.windows.push(window); }
+            }
+
+            WmEvent::WindowFocused(id) => {
+                for desktop in &mut self.desktops {
+                    if let Some(i) = desktop.windows.iter().position(|w| w.id == id) {
+                        let window = desktop.windows.remove(i);
+                        desktop.windows.insert(0, window);
+                    }
+                }
+            }
+
+            WmEvent::WindowMoved { id, desktop } => {
+                if let Some(window) = self.take_window(id) {
+                    if let Some(d) = self.desktop_mut(desktop) { d.windows.push(window); }
+                }
+            }
+
+            WmEvent::WindowRemoved(id) => { self.take_window(id); }
+
+            WmEvent::WindowRetitled(window) => {
+                for desktop in &mut self.desktops {
+                    if let Some(w) = desktop.windows.iter_mut().find(|w| w.id == window.id) {
+                        *w = window;
+                        break;
+                    }
+                }
+            }
+
+            WmEvent::WindowUrgent { id, urgent } => {
+                if let Some(w) = self.desktops.iter_mut().flat_map(|d| d.windows.iter_mut()).find(|w| w.id == id) {
+                    w.urgent = urgent;
+                }
+            }
+        }
+    }
+}
+
+// everything that can make the bar change
+pub enum RenderMsg {
+    Wm(xcb::Result<Option<Vec<WmEvent>>>),
+    ConfigReloaded
+}
+
+// tells the render thread the wm is gone however the backend thread ends, a panic included,
+// the config thread keeps the channel open so it would wait forever otherwise
+struct BackendGone(mpsc::Sender<RenderMsg>);
+
+impl Drop for BackendGone {
+    fn drop(&mut self) {
+        let _ = self.0.send(RenderMsg::Wm(Ok(None)));
+    }
+}
+
+pub fn thread_render<B: Backend + Send + 'static>(mut backend: B, icons: Arc<Mutex<Icons>>, mut output: Output, tx: mpsc::Sender<RenderMsg>, rx: mpsc::Receiver<RenderMsg>) -> xcb::Result<()> {
+    let mut workspaces = Workspaces::new();
+
+    // the backend blocks, so it gets its own thread and we only wait on the channel
+    thread::spawn(move || {
+        let _gone = BackendGone(tx.clone());
+        loop {
+            let events = backend.poll();
+            let done = !matches!(events, Ok(Some(_)));
+            if tx.send(RenderMsg::Wm(events)).is_err() || done { break; }
+        }
+    });
+
+    for msg in rx {
+        match msg {
+            RenderMsg::Wm(events) => {
+                let events = match events? {
+                    Some(e) => e,
+                    None => break
+                };
+
+                if events.is_empty() { continue; }
+
+                for event in events {
+                    workspaces.apply(event);
+                }
+            }
+
+            RenderMsg::ConfigReloaded => ()
+        }
+
+        output.write(&workspaces, &icons.lock().expect("Failed to aquire lock"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripted::ScriptedBackend;
+
+    // every batch of the script applied in order, like thread_render would minus the rendering
+    fn play(script: &str) -> Workspaces {
+        let mut backend = ScriptedBackend::from_script(script).unwrap();
+        let mut workspaces = Workspaces::new();
+        while let Some(events) = backend.poll().unwrap() {
+            for event in events { workspaces.apply(event); }
+        }
+
+        workspaces
+    }
+
+    // desktop: classes of its windows, most recently focused first
+    fn layout(workspaces: &Workspaces) -> Vec<String> {
+        workspaces.desktops.iter()
+            .map(|d| format!("{}: {}", d.name, d.windows.iter().map(|w| w.class.as_str()).collect::<Vec<_>>().join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn focused_window_goes_first() {
+        let workspaces = play("desktop_add I\nwindow_add I 1 firefox a\nwindow_add I 2 kitty b\nwindow_focus 2\nwindow_focus 1\nwindow_focus 2");
+        assert_eq!(layout(&workspaces), ["I: kitty firefox"]);
+    }
+
+    #[test]
+    fn windows_move_and_go_away() {
+        let workspaces = play(concat!(
+            "desktop_add I; desktop_add II\n",
+            "window_add I 1 firefox a; window_add I 2 kitty b; window_add I 3 mpv c\n",
+            "window_move 1 II\n",
+            "window_remove 3\n",
+            "window_retitle 2 kitty other title\n",
+            "window_remove 42\n"
+        ));
+
+        assert_eq!(layout(&workspaces), ["I: kitty", "II: firefox"]);
+        assert_eq!(workspaces.desktops[0].windows[0].title, "other title");
+    }
+
+    #[test]
+    fn renaming_the_focused_desktop_keeps_it_focused() {
+        let workspaces = play("desktop_add I; desktop_add II; desktop_focus II\ndesktop_rename II web\ndesktop_remove I");
+        assert_eq!(layout(&workspaces), ["web: "]);
+        assert_eq!(workspaces.focused, Some(2));
+    }
+
+    #[test]
+    fn transferred_desktops_go_last() {
+        let workspaces = play("desktop_add I; desktop_add II; desktop_add III\ndesktop_transfer I m1; desktop_transfer II m1; desktop_transfer III m2\ndesktop_transfer I m2");
+
+        let placed :Vec<(&str, &str)> = workspaces.desktops.iter().map(|d| (d.name.as_str(), d.monitor.as_str())).collect();
+        assert_eq!(placed, [("II", "m1"), ("III", "m2"), ("I", "m2")]);
+    }
+
+    #[test]
+    fn urgency_follows_the_window() {
+        let workspaces = play("desktop_add I; desktop_add II\nwindow_add I 1 discord a\nwindow_urgent 1 on\nwindow_move 1 II");
+        assert!(!workspaces.desktops[0].is_urgent());
+        assert!(workspaces.desktops[1].is_urgent());
+
+        let workspaces = play("desktop_add I\nwindow_add I 1 discord a\nwindow_urgent 1 on\nwindow_urgent 1 off");
+        assert!(!workspaces.desktops[0].is_urgent());
+    }
+
+    fn desktop(id: u32, name: &str, monitor: &str) -> Desktop {
+        Desktop { id, name: name.to_string(), monitor: monitor.to_string(), windows: vec![] }
+    }
+
+    #[test]
+    fn swap_across_monitors_swaps_monitors() {
+        let mut workspaces = Workspaces { desktops: vec![desktop(1, "I", "m1"), desktop(2, "II", "m2")], focused: Some(1) };
+        workspaces.apply(WmEvent::DesktopsSwapped(1, 2));
+
+        let placed :Vec<(&str, &str)> = workspaces.desktops.iter().map(|d| (d.name.as_str(), d.monitor.as_str())).collect();
+        assert_eq!(placed, [("II", "m1"), ("I", "m2")]);
+    }
+
+    #[test]
+    fn desktops_with_the_same_name_stay_apart() {
+        // what bspwm does when a second monitor shows up
+        let workspaces = play(concat!(
+            "desktop_add Desktop; desktop_transfer Desktop m1; window_add Desktop 1 firefox a\n",
+            "desktop_add Desktop; desktop_transfer %2 m2; window_add %2 2 kitty b\n",
+            "desktop_focus %2\n",
+            "desktop_rename Desktop web\n"
+        ));
+
+        let placed :Vec<(u32, &str, &str)> = workspaces.desktops.iter().map(|d| (d.id, d.name.as_str(), d.monitor.as_str())).collect();
+        assert_eq!(placed, [(1, "web", "m1"), (2, "Desktop", "m2")]);
+        assert_eq!(layout(&workspaces), ["web: firefox", "Desktop: kitty"]);
+        assert_eq!(workspaces.focused, Some(2));
+    }
+
+    struct Crashing;
+
+    impl Backend for Crashing {
+        fn poll(&mut self) -> xcb::Result<Option<Vec<WmEvent>>> {
+            panic!("the backend fell over");
+        }
+    }
+
+    #[test]
+    fn a_crashed_backend_ends_the_render_thread() {
+        let (tx, rx) = mpsc::channel();
+        // what the config thread would be holding on to
+        let _config_tx = tx.clone();
+        let output = Output::new(crate::output::Target::Stdout, crate::output::Format::Fmt, false);
+
+        assert!(thread_render(Crashing, Arc::new(Mutex::new(Icons::new())), output, tx, rx).is_ok());
+    }
+}
@@ -1,103 +1,311 @@
-use bspc_rs::events::{subscribe, DesktopEvent, Event, NodeEvent, Subscription};
-use bspc_rs::selectors::{DesktopSelector, NodeSelector};
-use std::sync::{ Mutex, Arc };
-use crate::icons::Icons;
-use crate::window::{Atoms, KnownWindow, print_icons};
-use std::collections::{ BTreeMap, HashMap };
-
-pub fn thread_bspwm(icons: Arc<Mutex<Icons>>, args: Vec<String>) -> xcb::Result<()> {
-    let (x_conn, screen_num) = xcb::Connection::connect(None)?;
-    let setup = x_conn.get_setup();
-    let _screen = setup.roots().nth(screen_num as usize).unwrap();
-    let atoms = Atoms::intern_all(&x_conn)?;
-    
-
-    let subscriptions = vec![ Subscription::NodeTransfer, Subscription::NodeFocus, Subscription::NodeRemove, Subscription::NodeAdd, Subscription::DesktopFocus ];
-    let mut subscriber = subscribe(false, None, &subscriptions).unwrap();
-
-    let mut desktops :HashMap<u32, String> = HashMap::new();
-    let mut windows :BTreeMap<String, Option<KnownWindow>> = BTreeMap::new();
-    let mut focused_desktop :String = String::new();
-
-    for workspace in args {
-        if let Ok(desktop_id) = bspc_rs::query::query_desktops(false, None, None, Some(DesktopSelector(&workspace)), None) {
-            focused_desktop = workspace.clone();
-            desktops.insert(desktop_id[0], workspace.clone());
-            if let Ok(nodes) = bspc_rs::query::query_nodes(Some(NodeSelector(".window")), None, Some(DesktopSelector(&workspace)), None) {
-                let kw = KnownWindow::new(&x_conn, &atoms, &nodes[0]);
-                windows.insert(workspace, Some(kw));
-            } else {
-                windows.insert(workspace, None);
+use bspc_rs::errors::ReplyError;
+use bspc_rs::events::{subscribe, DesktopEvent, Event, MonitorEvent, NodeEvent, Subscription};
+use bspc_rs::properties::{Flag, Switch};
+use bspc_rs::selectors::{DesktopSelector, MonitorSelector, NodeSelector};
+use bspc_rs::tree::Tree;
+use crate::backend::{Backend, WmEvent};
+use crate::window::{Atoms, KnownWindow, watch_window, unwatch_window};
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+use std::sync::{ Arc, mpsc };
+use std::thread;
+use xcb::{x, Xid};
+
+// bspc and X both block, so each gets a thread that forwards into one channel
+enum Incoming {
+    Bspwm(Result<Event, ReplyError>),
+    Property(u32, x::Atom),
+    XError(xcb::Error)
+}
+
+fn query_windows(desktop: &str) -> Vec<u32> {
+    bspc_rs::query::query_nodes(Some(NodeSelector(".window")), None, Some(DesktopSelector(desktop)), None).unwrap_or_default()
+}
+
+// every desktop bspwm knows about with the name of its monitor, in the order bspwm keeps them
+fn query_all_desktops() -> Vec<(u32, String, String)> {
+    let mut desktops = vec![];
+    let monitors = bspc_rs::query::query_monitors(false, None, None, None, None).unwrap_or_default();
+
+    for monitor in monitors {
+        if let Ok(Tree::Monitor(m)) = bspc_rs::query::query_tree(Some(MonitorSelector(&format!("{}", monitor))), None, None) {
+            desktops.extend(m.desktops.into_iter().map(|d| (d.id, d.name, m.name.clone())));
+        }
+    }
+
+    desktops
+}
+
+// bspc-rs has no way to focus a desktop, so clicks on a bar go through the bspc binary
+pub fn focus_desktop(selector: &str) {
+    if let Err(e) = Command::new("bspc").args(["desktop", "-f", selector]).status() {
+        eprintln!("bspc error: {e}");
+    }
+}
+
+pub struct BspwmBackend {
+    x_conn: Arc<xcb::Connection>,
+    atoms: Atoms,
+    incoming: mpsc::Receiver<Incoming>,
+    workspaces: Vec<String>, // empty means every desktop
+    desktops: HashMap<u32, String>,
+    monitors: HashMap<u32, String>, // desktop id -> monitor name
+    windows: HashMap<u32, u32>, // window id -> desktop id, only for the desktops we track
+    urgent: HashSet<u32>, // windows bspwm flagged urgent
+    initial: Option<Vec<WmEvent>>
+}
+
+impl BspwmBackend {
+    pub fn new(args: Vec<String>) -> xcb::Result<Self> {
+        let (x_conn, screen_num) = xcb::Connection::connect(None)?;
+        let setup = x_conn.get_setup();
+        let _screen = setup.roots().nth(screen_num as usize).unwrap();
+        let atoms = Atoms::intern_all(&x_conn)?;
+
+        let subscriptions = vec![
+            Subscription::NodeTransfer, Subscription::NodeFocus, Subscription::NodeRemove, Subscription::NodeAdd,
+            Subscription::NodeFlag, // urgency is a flag, node_state only covers tiled/floating/fullscreen
+            Subscription::DesktopFocus, Subscription::DesktopAdd, Subscription::DesktopRemove, Subscription::DesktopRename, Subscription::DesktopSwap,
+            Subscription::DesktopTransfer, Subscription::Monitor // bspc-rs has no monitor_remove subscription on its own
+        ];
+        let mut subscriber = subscribe(false, None, &subscriptions).unwrap();
+
+        let x_conn = Arc::new(x_conn);
+        let (tx, incoming) = mpsc::channel::<Incoming>();
+
+        let bspwm_tx = tx.clone();
+        thread::spawn(move || {
+            for event in subscriber.events() {
+                let failed = event.is_err();
+                if bspwm_tx.send(Incoming::Bspwm(event)).is_err() || failed { break; }
+            }
+        });
+
+        let x_conn_events = x_conn.clone();
+        thread::spawn(move || loop {
+            let sent = match x_conn_events.wait_for_event() {
+                Ok(xcb::Event::X(x::Event::PropertyNotify(ev))) => tx.send(Incoming::Property(ev.window().resource_id(), ev.atom())),
+                Ok(_) => Ok(()),
+                Err(xcb::Error::Protocol(_)) => Ok(()), // the window was gone before we got to it
+                Err(e) => { let _ = tx.send(Incoming::XError(e)); break; }
+            };
+
+            if sent.is_err() { break; }
+        });
+
+        let mut backend = Self { x_conn, atoms, incoming, workspaces: args.clone(), desktops: HashMap::new(), monitors: HashMap::new(), windows: HashMap::new(), urgent: HashSet::new(), initial: None };
+        let mut events = vec![];
+
+        let urgent = bspc_rs::query::query_nodes(Some(NodeSelector(".window.urgent")), None, None, None).unwrap_or_default();
+        backend.urgent.extend(urgent);
+
+        if args.is_empty() {
+            for (desktop_id, name, _) in query_all_desktops() {
+                backend.add_desktop(desktop_id, name, &mut events);
+            }
+        }
+
+        for workspace in args {
+            if let Ok(desktop_id) = bspc_rs::query::query_desktops(false, None, None, Some(DesktopSelector(&workspace)), None) {
+                backend.add_desktop(desktop_id[0], workspace, &mut events);
+            }
+        }
+
+        backend.refresh_monitors(&mut events);
+
+        if let Ok(focused) = bspc_rs::query::query_desktops(false, None, None, Some(DesktopSelector("focused")), None) {
+            if backend.desktops.contains_key(&focused[0]) {
+                events.push(WmEvent::DesktopFocused(focused[0]));
+            }
+        }
+
+        backend.initial = Some(events);
+        Ok(backend)
+    }
+
+    fn add_desktop(&mut self, desktop_id: u32, name: String, events: &mut Vec<WmEvent>) {
+        self.desktops.insert(desktop_id, name.clone());
+        events.push(WmEvent::DesktopAdded { id: desktop_id, name });
+        self.refresh_desktop(desktop_id, events);
+    }
+
+    // monitors getting added, removed, renamed or swapped can move any number of desktops around, so just ask again
+    fn refresh_monitors(&mut self, events: &mut Vec<WmEvent>) {
+        for (desktop_id, _, monitor) in query_all_desktops() {
+            if !self.desktops.contains_key(&desktop_id) { continue; }
+
+            if self.monitors.get(&desktop_id) != Some(&monitor) {
+                self.monitors.insert(desktop_id, monitor.clone());
+                events.push(WmEvent::DesktopTransferred { desktop: desktop_id, monitor });
             }
         }
     }
 
-    print_icons(&windows, &focused_desktop, &icons.lock().expect("Failed to aquire lock"));
+    fn remove_desktop(&mut self, desktop_id: u32, events: &mut Vec<WmEvent>) {
+        if self.desktops.remove(&desktop_id).is_none() { return; }
 
-    macro_rules! skip_workspaces {
-        ($w: expr) => {
-            if !desktops.contains_key(&$w) { continue; }
-        };
+        self.monitors.remove(&desktop_id);
+
+        let x_conn = &self.x_conn;
+        self.windows.retain(|id, desktop| {
+            if *desktop != desktop_id { return true; }
+            unwatch_window(x_conn, *id);
+            false
+        });
+
+        events.push(WmEvent::DesktopRemoved(desktop_id));
     }
 
-    macro_rules! change_window {
-        ($id: expr, $window: expr) => {
-            windows.insert(desktops.get($id).unwrap().to_string(), $window)
-        };
+    // bspwm already turns WM_HINTS urgency into its urgent flag, and clears the flag on focus even when
+    // the program never clears its hint, so the flag is what counts here
+    fn read_window(&self, id: u32) -> KnownWindow {
+        let mut window = KnownWindow::new(&self.x_conn, &self.atoms, &id);
+        window.urgent = self.urgent.contains(&id);
+        window
     }
 
-    for event in subscriber.events() {
-        match event.unwrap() {
+    // compares what bspwm says is on the desktop with what we know, refresh the destination of a move before its source
+    fn refresh_desktop(&mut self, desktop_id: u32, events: &mut Vec<WmEvent>) {
+        if !self.desktops.contains_key(&desktop_id) { return; }
+
+        let ids = query_windows(&format!("{}", desktop_id));
+        let x_conn = &self.x_conn;
+        let urgent = &mut self.urgent;
+        self.windows.retain(|id, desktop| {
+            if *desktop != desktop_id || ids.contains(id) { return true; }
+            unwatch_window(x_conn, *id);
+            urgent.remove(id);
+            events.push(WmEvent::WindowRemoved(*id));
+            false
+        });
+
+        for id in ids {
+            match self.windows.insert(id, desktop_id) {
+                Some(old) if old == desktop_id => (),
+                Some(_) => events.push(WmEvent::WindowMoved { id, desktop: desktop_id }),
+                None => {
+                    watch_window(&self.x_conn, id);
+                    events.push(WmEvent::WindowAdded { desktop: desktop_id, window: self.read_window(id) });
+                }
+            }
+        }
+
+        let _ = self.x_conn.flush();
+    }
+}
+
+impl Backend for BspwmBackend {
+    fn poll(&mut self) -> xcb::Result<Option<Vec<WmEvent>>> {
+        if let Some(events) = self.initial.take() {
+            return Ok(Some(events));
+        }
+
+        let mut events = vec![];
+
+        let event = match self.incoming.recv() {
+            Ok(Incoming::Bspwm(Ok(e))) => e,
+            Ok(Incoming::Bspwm(Err(e))) => { eprintln!("bspc error: {e}"); return Ok(None) }
+            Ok(Incoming::Property(id, atom)) => {
+                if self.windows.contains_key(&id) && self.atoms.is_window_property(atom) {
+                    events.push(WmEvent::WindowRetitled(self.read_window(id)));
+                }
+                return Ok(Some(events));
+            }
+            Ok(Incoming::XError(e)) => return Err(e),
+            Err(_) => return Ok(None)
+        };
+
+        macro_rules! skip_workspaces {
+            ($w: expr) => {
+                if !self.desktops.contains_key(&$w) { return Ok(Some(events)); }
+            };
+        }
+
+        match event {
             Event::NodeEvent(event) => match event {
                 NodeEvent::NodeTransfer(node_info) => {
-                    if desktops.contains_key(&node_info.src_desktop_id) {
-                        if let Ok(nodes) = bspc_rs::query::query_nodes(Some(NodeSelector(".window")), None, Some(DesktopSelector(&format!("{}", node_info.src_desktop_id))), None) {
-                            change_window!(&node_info.src_desktop_id, Some(KnownWindow::new(&x_conn, &atoms, &nodes[0])));
-                        } else {
-                            change_window!(&node_info.src_desktop_id, None);
-                        }
-                    }
-                    
-                    skip_workspaces!(node_info.dst_desktop_id);
-                    change_window!(&node_info.dst_desktop_id, Some(KnownWindow::new(&x_conn, &atoms, &node_info.src_node_id)));
+                    self.refresh_desktop(node_info.dst_desktop_id, &mut events);
+                    self.refresh_desktop(node_info.src_desktop_id, &mut events);
                 }
 
                 NodeEvent::NodeAdd(node_info) => {
                     skip_workspaces!(node_info.desktop_id);
-                    change_window!(&node_info.desktop_id, Some(KnownWindow::new(&x_conn, &atoms, &node_info.node_id)));
+                    self.refresh_desktop(node_info.desktop_id, &mut events);
                 }
 
                 NodeEvent::NodeFocus(node_info) => {
                     skip_workspaces!(node_info.desktop_id);
-                    change_window!(&node_info.desktop_id, Some(KnownWindow::new(&x_conn, &atoms, &node_info.node_id)));
-
+                    if !self.windows.contains_key(&node_info.node_id) {
+                        self.refresh_desktop(node_info.desktop_id, &mut events);
+                    }
+                    events.push(WmEvent::WindowFocused(node_info.node_id));
                 }
 
                 NodeEvent::NodeRemove(node_info) => {
                     skip_workspaces!(node_info.desktop_id);
-                    if let Ok(nodes) = bspc_rs::query::query_nodes(Some(NodeSelector(".window")), None, Some(DesktopSelector(&format!("{}", node_info.desktop_id))), None) {
-                        change_window!(&node_info.desktop_id, Some(KnownWindow::new(&x_conn, &atoms, &nodes[0])));
-                    } else {
-                        change_window!(&node_info.desktop_id, None);
-                    }
+                    self.refresh_desktop(node_info.desktop_id, &mut events);
+                }
+
+                NodeEvent::NodeFlag(node_info) => {
+                    if !matches!(node_info.flag, Flag::Urgent) { return Ok(Some(events)); }
+                    let urgent = matches!(node_info.switch, Switch::On);
+                    if urgent { self.urgent.insert(node_info.node_id); } else { self.urgent.remove(&node_info.node_id); }
+
+                    skip_workspaces!(node_info.desktop_id);
+                    events.push(WmEvent::WindowUrgent { id: node_info.node_id, urgent });
                 }
 
                 _ => unreachable!()
             }
 
-            Event::DesktopEvent(event) => {
-                match event {
-                    DesktopEvent::DesktopFocus(desktop_info) => { skip_workspaces!(desktop_info.desktop_id); focused_desktop = desktops.get(&desktop_info.desktop_id).unwrap().to_string() },
-                    _ => unreachable!()
+            Event::DesktopEvent(event) => match event {
+                DesktopEvent::DesktopFocus(desktop_info) => {
+                    skip_workspaces!(desktop_info.desktop_id);
+                    events.push(WmEvent::DesktopFocused(desktop_info.desktop_id));
+                }
+
+                DesktopEvent::DesktopAdd(desktop_info) => {
+                    if self.workspaces.is_empty() || self.workspaces.contains(&desktop_info.desktop_name) {
+                        self.add_desktop(desktop_info.desktop_id, desktop_info.desktop_name, &mut events);
+                        self.refresh_monitors(&mut events);
+                    }
+                }
+
+                DesktopEvent::DesktopTransfer(desktop_info) => {
+                    skip_workspaces!(desktop_info.src_desktop_id);
+                    self.refresh_monitors(&mut events);
+                }
+
+                DesktopEvent::DesktopRemove(desktop_info) => self.remove_desktop(desktop_info.desktop_id, &mut events),
+
+                DesktopEvent::DesktopRename(desktop_info) => {
+                    skip_workspaces!(desktop_info.desktop_id);
+                    self.desktops.insert(desktop_info.desktop_id, desktop_info.new_name.clone());
+                    events.push(WmEvent::DesktopRenamed { id: desktop_info.desktop_id, name: desktop_info.new_name });
                 }
+
+                DesktopEvent::DesktopSwap(desktop_info) => {
+                    skip_workspaces!(desktop_info.src_desktop_id);
+                    skip_workspaces!(desktop_info.dst_desktop_id);
+                    let src_monitor = self.monitors.remove(&desktop_info.src_desktop_id);
+                    let dst_monitor = self.monitors.remove(&desktop_info.dst_desktop_id);
+                    if let Some(m) = dst_monitor { self.monitors.insert(desktop_info.src_desktop_id, m); }
+                    if let Some(m) = src_monitor { self.monitors.insert(desktop_info.dst_desktop_id, m); }
+
+                    events.push(WmEvent::DesktopsSwapped(desktop_info.src_desktop_id, desktop_info.dst_desktop_id));
+                }
+
+                _ => unreachable!()
             }
+
+            Event::MonitorEvent(event) => match event {
+                MonitorEvent::MonitorAdd(_) | MonitorEvent::MonitorRemove(_) | MonitorEvent::MonitorRename(_) | MonitorEvent::MonitorSwap(_) => self.refresh_monitors(&mut events),
+                _ => ()
+            }
+
             _ => unreachable!()
         }
 
-       print_icons(&windows, &focused_desktop, &icons.lock().unwrap());
+        Ok(Some(events))
     }
-
-    Ok(())
-
 }
-
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use crate::config::{load_statements, Origin, Statements};
+use crate::parser::{DesktopSetting, ParseError, Stmt};
+use crate::window::FMT_KEYS;
+
+// points at the whole statement, or at `needle` if it has it
+fn diagnostic(loaded: &Statements, origin: Origin, needle: Option<&str>, message: String) -> ParseError {
+    let source = &loaded.sources[origin.source];
+    ParseError { file: Some(source.path.clone()), ..ParseError::at(&source.text, origin.line, origin.last_line, needle, message) }
+}
+
+// "line 3", or "line 3 of colors.i" when it's in another file than the statement being warned about
+fn place(loaded: &Statements, origin: Origin, from: Origin) -> String {
+    if origin.source == from.source { return format!("line {}", origin.line); }
+    format!("line {} of {}", origin.line, loaded.sources[origin.source].path)
+}
+
+// #rgb, #argb, #rrggbb or #aarrggbb, same as polybar and lemonbar take
+fn valid_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false
+    }
+}
+
+fn is_literal(pattern: &str) -> bool {
+    !pattern.chars().any(|c| "\\.+*?()|[]{}^$".contains(c))
+}
+
+fn placeholders(fmt: &str) -> Vec<String> {
+    let mut keys = vec![];
+    let mut chars = fmt.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); }
+            '{' => {
+                let key :String = chars.by_ref().take_while(|c| *c != '}').collect();
+                keys.push(key.split(':').next().unwrap_or_default().to_string());
+            }
+            _ => ()
+        }
+    }
+
+    keys
+}
+
+// placeholders render_desktop has nothing for, strfmt would fail on every line with one of them
+pub fn unknown_placeholders(loaded: &Statements, origin: Origin, fmt: &str) -> Vec<ParseError> {
+    let mut available = FMT_KEYS.to_vec();
+    available.extend(loaded.stmts.iter().flat_map(|(_, stmt)| stmt.capture_names()));
+
+    placeholders(fmt).iter()
+        .filter(|key| !available.contains(&key.as_str()))
+        .map(|key| diagnostic(loaded, origin, Some(&format!("{{{key}")), format!("unknown placeholder `{{{key}}}` in fmt, available are: {}", available.join(", "))))
+        .collect()
+}
+
+fn lint(loaded: &Statements) -> (Vec<ParseError>, Vec<ParseError>) {
+    let stmts = &loaded.stmts;
+    let mut errors = vec![];
+    let mut warnings = vec![];
+    let mut singletons :HashMap<String, Origin> = HashMap::new();
+    let mut fmt = None;
+    let mut icon_format = vec![];
+
+    for (i, (line, stmt)) in stmts.iter().enumerate() {
+        let desktop_kind;
+        let (kind, icon) = match stmt {
+            Stmt::Default(icon) => ("default", Some(icon)),
+            Stmt::Empty(icon) => ("empty", Some(icon)),
+            Stmt::Desktop(name, setting) => {
+                let (what, icon) = match setting {
+                    DesktopSetting::Label(_) => ("label", None),
+                    DesktopSetting::Default(icon) => ("default", Some(icon)),
+                    DesktopSetting::Empty(icon) => ("empty", Some(icon))
+                };
+                desktop_kind = format!("desktop \"{name}\" {what}");
+                (desktop_kind.as_str(), icon)
+            }
+            Stmt::FmtBefore(_) => ("before_fmt", None),
+            Stmt::Fmt(f) => { fmt = Some((*line, f)); ("fmt", None) }
+            Stmt::FmtAfter(_) => ("after_fmt", None),
+            Stmt::IconBefore(_) => { icon_format.push(*line); ("before_icon", None) }
+            Stmt::IconAfter(_) => { icon_format.push(*line); ("after_icon", None) }
+            Stmt::Compound(_, icon) => ("compound", Some(icon)),
+            Stmt::None | Stmt::Include(_) => continue,
+            rule => rule.rule().map(|(p, i)| (p.keyword(), Some(i))).unwrap()
+        };
+
+        if let Some(icon) = icon {
+            for color in icon.color.iter().chain(icon.fcolor.iter()).chain(icon.ucolor.iter()) {
+                if !valid_color(color) {
+                    warnings.push(diagnostic(loaded, *line, Some(color), format!("unknown color `{color}`, expected #rgb, #argb, #rrggbb or #aarrggbb")));
+                }
+            }
+        }
+
+        if let Some((_, icon)) = stmt.rule() {
+            // a plain string is easy to test against every rule above it, real regexes are not
+            let shadowed_by = stmts[..i].iter().find(|(_, earlier)| match earlier.rule() {
+                Some((p, e)) if p.keyword() == kind => e.pattern() == icon.pattern() || (is_literal(icon.pattern()) && e.matches(icon.pattern())),
+                _ => false
+            });
+
+            if let Some((earlier, _)) = shadowed_by {
+                warnings.push(diagnostic(loaded, *line, None, format!("unreachable rule, everything it matches is already matched by the {kind} rule on {}", place(loaded, *earlier, *line))));
+            }
+            continue;
+        }
+
+        if let Stmt::Compound(..) = stmt { continue; }
+
+        if let Some(earlier) = singletons.insert(kind.to_string(), *line) {
+            warnings.push(diagnostic(loaded, earlier, None, format!("unused `{kind}`, it is overridden on {}", place(loaded, *line, earlier))));
+        }
+    }
+
+    if let Some((line, fmt)) = fmt {
+        errors.extend(unknown_placeholders(loaded, line, fmt));
+
+        if !placeholders(fmt).iter().any(|k| k == "icons") {
+            for line in icon_format {
+                warnings.push(diagnostic(loaded, line, None, "unused statement, fmt has no `{icons}` in it".to_string()));
+            }
+        }
+    }
+
+    (errors, warnings)
+}
+
+// exit code: 0 when clean, 1 with errors, 2 with only warnings
+pub fn check_config(path: &str) -> i32 {
+    let mut loaded = match load_statements(path) {
+        Ok(l) => l,
+        Err(e) => { eprintln!("{e}"); return 1; }
+    };
+
+    let mut errors = std::mem::take(&mut loaded.errors);
+    let (lint_errors, mut warnings) = lint(&loaded);
+    errors.extend(lint_errors);
+
+    // the file that was asked about first, then whatever it includes
+    let order = |e: &ParseError| (e.file.as_deref() != Some(path), e.file.clone(), e.line, e.column);
+    errors.sort_by_key(order);
+    warnings.sort_by_key(order);
+
+    for w in &warnings {
+        eprintln!("{}\n", w.render_as("warning", path));
+    }
+
+    for e in &errors {
+        eprintln!("{}\n", e.render(path));
+    }
+
+    let plural = |n: usize, word: &str| format!("{n} {word}{}", if n == 1 { "" } else { "s" });
+    match (errors.len(), warnings.len()) {
+        (0, 0) => { eprintln!("{path}: ok"); 0 }
+        (0, w) => { eprintln!("{path}: {}", plural(w, "warning")); 2 }
+        (e, w) => { eprintln!("{path}: {}, {}", plural(e, "error"), plural(w, "warning")); 1 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_path(name: &str, config: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("bspwm-workspace-icons-check-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.i"), config).unwrap();
+        dir.join("config.i")
+    }
+
+    // errors and warnings as `line:column message`
+    fn lint_config(name: &str, config: &str) -> (Vec<String>, Vec<String>) {
+        let path = config_path(name, config);
+        let loaded = load_statements(path.to_str().unwrap()).unwrap();
+        assert!(loaded.errors.is_empty());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let describe = |diagnostics: Vec<ParseError>| diagnostics.iter().map(|d| format!("{}:{} {}", d.line, d.column, d.message)).collect();
+        let (errors, warnings) = lint(&loaded);
+        (describe(errors), describe(warnings))
+    }
+
+    #[test]
+    fn rules_nothing_can_reach() {
+        let (errors, warnings) = lint_config("unreachable", concat!(
+            "class \"firefox\" \"F\"\n",
+            "class \"fire.*\" \"X\"\n",
+            "  class \"firefox\" \"G\"\n",
+            "class \"firefox-esr\" \"E\"\n",
+            "title \"firefox\" \"T\"\n",
+            "class \"fire.+\" \"Y\"\n"
+        ));
+
+        assert!(errors.is_empty());
+        assert_eq!(warnings, [
+            "3:3 unreachable rule, everything it matches is already matched by the class rule on line 1",
+            "4:1 unreachable rule, everything it matches is already matched by the class rule on line 2"
+        ]);
+    }
+
+    #[test]
+    fn colors_that_wont_render() {
+        let (_, warnings) = lint_config("colors", "default \"D\" color #ggg focused_color #12345 urgent_color #fff\nclass \"kitty\" \"K\" color #00ff00aa\n");
+
+        assert_eq!(warnings, [
+            "1:19 unknown color `#ggg`, expected #rgb, #argb, #rrggbb or #aarrggbb",
+            "1:38 unknown color `#12345`, expected #rgb, #argb, #rrggbb or #aarrggbb"
+        ]);
+    }
+
+    #[test]
+    fn statements_that_do_nothing() {
+        let (errors, warnings) = lint_config("unused", concat!(
+            "fmt \"{icons}\"\n",
+            "default \"D\"\n",
+            "before_icon \"<\"\n",
+            "desktop \"I\" label \"one\"\n",
+            "desktop \"II\" label \"two\"\n",
+            "desktop \"I\" label \"uno\"\n",
+            "default \"E\"\n",
+            "fmt \"{desktop}: {icon}\"\n"
+        ));
+
+        assert!(errors.is_empty());
+        assert_eq!(warnings, [
+            "4:1 unused `desktop \"I\" label`, it is overridden on line 6",
+            "2:1 unused `default`, it is overridden on line 7",
+            "1:1 unused `fmt`, it is overridden on line 8",
+            "3:1 unused statement, fmt has no `{icons}` in it"
+        ]);
+    }
+
+    #[test]
+    fn placeholders_fmt_has_nothing_for() {
+        let (errors, _) = lint_config("placeholders", "title \"(?P<project>.*) - Code\" \"C\"\nfmt \"{desktop}{project}{desktp}\"\n");
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("2:24 unknown placeholder `{desktp}` in fmt, available are: desktop, "), "{}", errors[0]);
+        assert!(errors[0].ends_with(", project"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn exit_codes() {
+        let check = |name: &str, config: &str| {
+            let path = config_path(name, config);
+            let code = check_config(path.to_str().unwrap());
+            std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+            code
+        };
+
+        assert_eq!(check("clean", "class \"firefox\" \"F\"\n"), 0);
+        assert_eq!(check("warnings", "class \"firefox\" \"F\"\nclass \"firefox\" \"G\"\n"), 2);
+        assert_eq!(check("lint-errors", "class \"firefox\" \"F\"\nclass \"firefox\" \"G\"\nfmt \"{nope}\"\n"), 1);
+        assert_eq!(check("parse-errors", "class \"firefox\"\n"), 1);
+        assert_eq!(check_config("/nonexistent/config.i"), 1);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fmt::{ Display, Formatter };
+use regex::Regex;
+use crate::parser::quote;
+use crate::window::KnownWindow;
+
+// fills in the named groups if the regex matches, unnamed ones aren't worth a placeholder
+pub fn named_captures(regex: &Regex, value: &str, out: &mut HashMap<String, String>) -> bool {
+    let Some(captures) = regex.captures(value) else { return false };
+
+    for name in regex.capture_names().flatten() {
+        let text = captures.name(name).map(|m| m.as_str()).unwrap_or_default();
+        out.insert(name.to_string(), text.to_string());
+    }
+
+    true
+}
+
+// the window properties a rule can look at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Property {
+    Class,
+    Instance,
+    Title,
+    Role,
+    Type,
+    Process,
+    ChildProcess
+}
+
+impl Property {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            Property::Class => "class",
+            Property::Instance => "instance",
+            Property::Title => "title",
+            Property::Role => "role",
+            Property::Type => "type",
+            Property::Process => "process",
+            Property::ChildProcess => "child_process"
+        }
+    }
+
+    // a rule matches when any of these do, most properties only have the one
+    pub fn of<'a>(&self, window: &'a KnownWindow) -> Vec<&'a str> {
+        match self {
+            Property::Class => vec![&window.class],
+            Property::Instance => vec![&window.instance],
+            Property::Title => vec![&window.title],
+            Property::Role => vec![&window.role],
+            Property::Type => vec![&window.window_type],
+            Property::Process => window.process.iter().map(String::as_str).collect(),
+            Property::ChildProcess => window.child_processes.iter().map(String::as_str).collect()
+        }
+    }
+}
+
+// `class "firefox" and not title ".*Private.*"`, not binds tighter than and, and tighter than or
+pub enum Condition {
+    Matches(Property, Regex),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>)
+}
+
+impl Condition {
+    pub fn new(property: Property, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Condition::Matches(property, Regex::new(&format!("^{}$", pattern))?))
+    }
+
+    // the regex as written in the config, only plain matches have one
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            Condition::Matches(_, regex) => {
+                let r = regex.as_str();
+                Some(&r[1..r.len() - 1])
+            }
+            _ => None
+        }
+    }
+
+    // whether anything in here looks at `property`
+    pub fn uses(&self, property: Property) -> bool {
+        match self {
+            Condition::Matches(p, _) => *p == property,
+            Condition::Not(c) => c.uses(property),
+            Condition::And(a, b) | Condition::Or(a, b) => a.uses(property) || b.uses(property)
+        }
+    }
+
+    pub fn capture_names<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Condition::Matches(_, regex) => out.extend(regex.capture_names().flatten()),
+            Condition::Not(c) => c.capture_names(out),
+            Condition::And(a, b) | Condition::Or(a, b) => { a.capture_names(out); b.capture_names(out); }
+        }
+    }
+
+    // captures of the parts that made the condition true, nothing under a not ever counts
+    pub fn captures(&self, window: &KnownWindow, out: &mut HashMap<String, String>) {
+        match self {
+            Condition::Matches(property, regex) => { property.of(window).iter().any(|v| named_captures(regex, v, out)); }
+            Condition::Not(_) => (),
+            Condition::And(a, b) => { a.captures(window, out); b.captures(window, out); }
+            Condition::Or(a, b) => if a.eval(window) { a.captures(window, out) } else { b.captures(window, out) }
+        }
+    }
+
+    pub fn eval(&self, window: &KnownWindow) -> bool {
+        match self {
+            Condition::Matches(property, regex) => property.of(window).iter().any(|v| regex.is_match(v)),
+            Condition::Not(c) => !c.eval(window),
+            Condition::And(a, b) => a.eval(window) && b.eval(window),
+            Condition::Or(a, b) => a.eval(window) || b.eval(window)
+        }
+    }
+}
+
+// written back the way it would be in the config, there are no parentheses so nothing needs adding
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Condition::Matches(property, _) => write!(f, "{} {}", property.keyword(), quote(self.pattern().unwrap_or_default())),
+            Condition::Not(c) => write!(f, "not {c}"),
+            Condition::And(a, b) => write!(f, "{a} and {b}"),
+            Condition::Or(a, b) => write!(f, "{a} or {b}")
+        }
+    }
+}
@@ -1,74 +1,472 @@
-use crate::parser::{Lexer, Parser, Stmt};
+use crate::parser::{Lexer, ParseError, Parser, Stmt};
 use crate::icons::Icons;
+use crate::check::unknown_placeholders;
+use crate::condition::Property;
+use crate::process::set_want_children;
+use crate::toml_config::{is_toml, parse_toml};
+use crate::backend::RenderMsg;
 use notify::{recommended_watcher, RecursiveMode, Watcher};
+use std::fmt::{ Display, Formatter };
+use std::path::{Path, PathBuf};
 use std::sync::{ Mutex, Arc, mpsc };
 
-fn read_config(icons: &mut Icons, path: &str) -> Result<(), std::io::Error> {
-    let config = match std::fs::read_to_string(path) {
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, Vec<ParseError>)
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "error: reading {path}: {e}"),
+            ConfigError::Parse(path, errors) => {
+                for e in errors {
+                    writeln!(f, "{}\n", e.render(path))?;
+                }
+
+                write!(f, "error: could not load {path} due to {} previous error{}", errors.len(), if errors.len() == 1 { "" } else { "s" })
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    // short enough to show in a bar
+    pub fn summary(&self) -> String {
+        match self {
+            ConfigError::Io(path, e) => format!("{path}: {e}"),
+            ConfigError::Parse(path, errors) => match errors.first() {
+                Some(e) if errors.len() > 1 => format!("{}:{e} (and {} more)", e.file.as_deref().unwrap_or(path), errors.len() - 1),
+                Some(e) => format!("{}:{e}", e.file.as_deref().unwrap_or(path)),
+                None => path.to_string()
+            }
+        }
+    }
+}
+
+// one file the config is made of, the one it was loaded from comes first
+pub struct Source {
+    pub path: String,
+    pub text: String
+}
+
+// where a statement came from, an index into Statements::sources and the lines it's on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Origin {
+    pub source: usize,
+    pub line: usize,
+    pub last_line: usize // the same as line unless it was continued onto the next ones
+}
+
+pub struct Statements {
+    pub sources: Vec<Source>,
+    pub stmts: Vec<(Origin, Stmt)>,
+    pub errors: Vec<ParseError>
+}
+
+// ~ and $VAR or ${VAR} get expanded, relative paths are relative to the file doing the including
+fn resolve_include(path: &str, from: &Path) -> Result<PathBuf, String> {
+    let mut expanded = String::new();
+    let mut chars = path.chars().peekable();
+
+    if path == "~" || path.starts_with("~/") {
+        expanded += &std::env::var("HOME").map_err(|_| "HOME is not set".to_string())?;
+        chars.next();
+    }
+
+    while let Some(c) = chars.next() {
+        if c != '$' { expanded.push(c); continue; }
+
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| if braced { *c != '}' } else { c.is_ascii_alphanumeric() || *c == '_' }) {
+            name.push(c);
+        }
+        if braced { chars.next(); }
+
+        expanded += &std::env::var(&name).map_err(|_| format!("environment variable `{name}` is not set"))?;
+    }
+
+    let expanded = PathBuf::from(expanded);
+    match from.parent() {
+        Some(dir) if expanded.is_relative() => Ok(dir.join(expanded)),
+        _ => Ok(expanded)
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or(path.to_path_buf())
+}
+
+// one parser goes through every file so variables carry over into (and out of) includes
+fn parse_source(parser: &mut Parser, loaded: &mut Statements, source: usize, stack: &mut Vec<PathBuf>) {
+    let Source { path, text } = &loaded.sources[source];
+    let (path, text) = (path.clone(), text.clone());
+
+    // a toml file can't include anything, so it's just its own statements
+    if is_toml(&path) {
+        let (stmts, errors) = parse_toml(&text);
+        loaded.stmts.extend(stmts.into_iter().map(|(line, last_line, s)| (Origin { source, line, last_line }, s)));
+        loaded.errors.extend(errors.into_iter().map(|e| ParseError { file: Some(path.clone()), ..e }));
+        return;
+    }
+
+    for statement in Lexer::new(&text) {
+        // keep going after a bad statement so every mistake gets reported at once
+        let parsed = statement.and_then(|tokens| {
+            let origin = Origin { source, line: tokens.first_line, last_line: tokens.last_line };
+            parser.parse(tokens).map(|s| (origin, s))
+        });
+
+        let (origin, include) = match parsed {
+            Ok((_, Stmt::None)) => continue,
+            Ok((origin, Stmt::Include(include))) => (origin, include),
+            Ok(parsed) => { loaded.stmts.push(parsed); continue; }
+            Err(e) => { loaded.errors.push(ParseError { file: Some(path.clone()), ..e }); continue; }
+        };
+
+        let error = |message: String| ParseError { file: Some(path.clone()), ..ParseError::at(&text, origin.line, origin.last_line, Some(&include), message) };
+
+        let target = match resolve_include(&include, Path::new(&path)) {
+            Ok(t) => t,
+            Err(e) => { loaded.errors.push(error(format!("can't include \"{include}\": {e}"))); continue; }
+        };
+
+        let target_canonical = canonical(&target);
+        if let Some(start) = stack.iter().position(|p| *p == target_canonical) {
+            let cycle :Vec<String> = stack[start..].iter().chain([&target_canonical]).map(|p| p.display().to_string()).collect();
+            loaded.errors.push(error(format!("include cycle: {}", cycle.join(" -> "))));
+            continue;
+        }
+
+        let text = match std::fs::read_to_string(&target) {
+            Ok(t) => t,
+            Err(e) => { loaded.errors.push(error(format!("can't read {}: {e}", target.display()))); continue; }
+        };
+
+        loaded.sources.push(Source { path: target.display().to_string(), text });
+        stack.push(target_canonical);
+        parse_source(parser, loaded, loaded.sources.len() - 1, stack);
+        stack.pop();
+    }
+}
+
+// every statement of the config and the files it includes, with where it came from and everything that went wrong on the way
+pub fn load_statements(path: &str) -> Result<Statements, ConfigError> {
+    let text = match std::fs::read_to_string(path) {
         Ok(c) => c,
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "error reading file"))
+        Err(e) => return Err(ConfigError::Io(path.to_string(), e))
+    };
+
+    let mut loaded = Statements { sources: vec![Source { path: path.to_string(), text }], stmts: vec![], errors: vec![] };
+    let mut parser = Parser::new();
+    parse_source(&mut parser, &mut loaded, 0, &mut vec![canonical(Path::new(path))]);
+
+    Ok(loaded)
+}
+
+// what the watcher has to keep an eye on, even when the config doesn't parse
+fn config_files(path: &str) -> Vec<PathBuf> {
+    let mut files = match load_statements(path) {
+        Ok(loaded) => loaded.sources.into_iter().map(|s| PathBuf::from(s.path)).collect(),
+        Err(_) => vec![PathBuf::from(path)]
     };
 
-    let lexer = Lexer::new();
-    let mut parser = Parser::new(lexer);
+    files.dedup();
+    files
+}
+
+fn parse_config(path: &str) -> Result<Icons, ConfigError> {
+    let mut loaded = load_statements(path)?;
+    let mut errors = std::mem::take(&mut loaded.errors);
+
+    // a typo in fmt parses fine but can't be rendered, so it can't count as a good config
+    let fmt = loaded.stmts.iter().rev().find_map(|(origin, stmt)| match stmt { Stmt::Fmt(f) => Some((*origin, f)), _ => None });
+    if let Some((origin, fmt)) = fmt {
+        errors.extend(unknown_placeholders(&loaded, origin, fmt));
+    }
 
-    *icons = Icons::new();
-    for line in config.lines() {
-        parser.feed_next_line(line);
-        let stmt = parser.parse()?;
+    let mut icons = Icons::new();
+    for (_, stmt) in loaded.stmts {
         match stmt {
             Stmt::Default(i) => icons.set_default(i),
             Stmt::Empty(i) => icons.set_empty(i),
+            Stmt::Desktop(name, setting) => icons.set_desktop(name, setting),
             Stmt::FmtBefore(f) => icons.set_before(f),
             Stmt::Fmt(f) => icons.set_fmt(f),
             Stmt::FmtAfter(f) => icons.set_after(f),
-            Stmt::None => continue,
+            Stmt::IconBefore(f) => icons.set_before_icon(f),
+            Stmt::IconAfter(f) => icons.set_after_icon(f),
+            Stmt::None | Stmt::Include(_) => continue,
             _ => icons.set_icon(stmt),
         }
     }
 
-    Ok(())
+    if !errors.is_empty() {
+        return Err(ConfigError::Parse(path.to_string(), errors));
+    }
+
+    Ok(icons)
 }
 
-pub fn thread_config(icons_arc: Arc<Mutex<Icons>>, path: &str) -> Result<(), std::io::Error> {
-    if let Ok(mut icons) = icons_arc.lock() {
-        read_config(&mut icons, path)?;
+// the new config only replaces the old one if all of it parsed, a typo mid-edit keeps the last good rules around
+pub fn read_config(icons: &mut Icons, path: &str) -> Result<(), ConfigError> {
+    match parse_config(path) {
+        Ok(mut new) => {
+            new.set_generation(icons.get_generation() + 1);
+            new.set_use_fallback(icons.get_use_fallback());
+            set_want_children(new.uses(Property::ChildProcess));
+            *icons = new;
+            Ok(())
+        }
+
+        Err(e) => {
+            // a file that's briefly gone is just how some editors save, not something to flash on the bar
+            if !matches!(e, ConfigError::Io(..)) { icons.set_error(Some(e.summary())); }
+            Err(e)
+        }
+    }
+}
+
+// includes can come and go with every edit, and editors that replace files on save drop the old watch
+fn watch_files(watcher: &mut impl Watcher, path: &str, watched: &mut Vec<PathBuf>) {
+    let files = config_files(path);
+
+    for old in watched.iter().filter(|old| !files.contains(old)) {
+        let _ = watcher.unwatch(old);
     }
 
+    for file in &files {
+        if let Err(e) = watcher.watch(file, RecursiveMode::Recursive) {
+            eprintln!("watch error: {}: {e}", file.display());
+        }
+    }
+
+    *watched = files;
+}
+
+pub fn thread_config(icons_arc: Arc<Mutex<Icons>>, path: &str, render: mpsc::Sender<RenderMsg>) -> Result<(), std::io::Error> {
     let (tx, rx) = mpsc::channel::<notify::Result<notify::Event>>();
     let mut watcher = match recommended_watcher(tx) {
         Ok(w) => w,
-        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+        Err(e) => return Err(std::io::Error::other(e))
     };
 
-    match watcher.watch(std::path::Path::new(path), RecursiveMode::Recursive) {
+    match watcher.watch(Path::new(path), RecursiveMode::Recursive) {
         Ok(()) => (),
-        Err(e) =>  return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+        Err(e) =>  return Err(std::io::Error::other(e))
     }
 
+    let mut watched = vec![];
+    watch_files(&mut watcher, path, &mut watched);
+
     for res in rx {
         match res {
             Ok(event) => match event.kind {
                 notify::EventKind::Modify(_) => {
                     if let Ok(mut icons) = icons_arc.lock() {
-                        let _ = read_config(&mut icons, path); //we let this error bc something something nvim does funny shit with files and it doesnt work
+                        match read_config(&mut icons, path) {
+                            Err(ConfigError::Io(..)) => (), //we let this error bc something something nvim does funny shit with files and it doesnt work
+                            Err(e) => eprintln!("{e}"),
+                            Ok(()) => (),
+                        }
                     }
+                    watch_files(&mut watcher, path, &mut watched);
+                    let _ = render.send(RenderMsg::ConfigReloaded);
                 },
                 notify::EventKind::Remove(_) => { // again bc nvim like, removes the files after modify? but its there? and it fuckes up everything
                     if let Ok(mut icons) = icons_arc.lock() {
-                        read_config(&mut icons, path)?;
-                        match watcher.watch(std::path::Path::new(path), RecursiveMode::Recursive) {
-                            Ok(()) => (),
-                            Err(e) =>  return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                        if let Err(e) = read_config(&mut icons, path) {
+                            eprintln!("{e}");
                         }
-
                     }
+                    watch_files(&mut watcher, path, &mut watched);
+                    let _ = render.send(RenderMsg::ConfigReloaded);
                 },
                 _ => (),
             }
-            Err(e) => println!("watch error: {:?}", e),
+            Err(e) => eprintln!("watch error: {:?}", e),
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bspwm-workspace-icons-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("config.i")
+    }
+
+    #[test]
+    fn only_good_reloads_replace_the_config() {
+        let path = config_path("reload");
+        let path_str = path.to_str().unwrap();
+        let mut icons = Icons::new();
+
+        std::fs::write(&path, "class \"firefox\" \"F\"\n").unwrap();
+        read_config(&mut icons, path_str).unwrap();
+        assert_eq!(icons.get_generation(), 1);
+        assert_eq!(icons.get_rules().len(), 1);
+
+        // a typo mid-edit, and a fmt that parses but couldn't be rendered
+        for bad in ["class \"firefox\" \"F\"\nclass \"kitty\"\n", "fmt \"[{desktp}]\"\nclass \"firefox\" \"F\"\nclass \"kitty\" \"K\"\n"] {
+            std::fs::write(&path, bad).unwrap();
+            assert!(matches!(read_config(&mut icons, path_str), Err(ConfigError::Parse(..))));
+            assert_eq!(icons.get_generation(), 1);
+            assert_eq!(icons.get_rules().len(), 1);
+            assert!(icons.get_error().is_some());
+        }
+
+        std::fs::write(&path, "class \"firefox\" \"F\"\nclass \"kitty\" \"K\"\n").unwrap();
+        read_config(&mut icons, path_str).unwrap();
+        assert_eq!(icons.get_generation(), 2);
+        assert_eq!(icons.get_rules().len(), 2);
+        assert_eq!(icons.get_error(), None);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_keeps_the_config_without_an_error() {
+        let path = config_path("missing");
+        let path_str = path.to_str().unwrap();
+        let mut icons = Icons::new();
+
+        std::fs::write(&path, "class \"firefox\" \"F\"\n").unwrap();
+        read_config(&mut icons, path_str).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(read_config(&mut icons, path_str), Err(ConfigError::Io(..))));
+        assert_eq!(icons.get_generation(), 1);
+        assert_eq!(icons.get_rules().len(), 1);
+        assert_eq!(icons.get_error(), None);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn include_paths_get_expanded() {
+        let home = std::env::var("HOME").unwrap();
+        let from = Path::new("/etc/icons/config.i");
+
+        assert_eq!(resolve_include("~/colors.i", from), Ok(PathBuf::from(format!("{home}/colors.i"))));
+        assert_eq!(resolve_include("$HOME/colors.i", from), Ok(PathBuf::from(format!("{home}/colors.i"))));
+        assert_eq!(resolve_include("${HOME}.d/colors.i", from), Ok(PathBuf::from(format!("{home}.d/colors.i"))));
+        assert_eq!(resolve_include("parts/colors.i", from), Ok(PathBuf::from("/etc/icons/parts/colors.i")));
+        assert_eq!(resolve_include("/usr/share/colors.i", from), Ok(PathBuf::from("/usr/share/colors.i")));
+        assert_eq!(
+            resolve_include("$BSPWM_WORKSPACE_ICONS_UNSET/colors.i", from),
+            Err("environment variable `BSPWM_WORKSPACE_ICONS_UNSET` is not set".to_string())
+        );
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_file_including_them() {
+        let path = config_path("include");
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir.join("parts")).unwrap();
+
+        std::fs::write(&path, "include \"parts/colors.i\"\nclass \"kitty\" \"K\" color $accent\n").unwrap();
+        std::fs::write(dir.join("parts/colors.i"), "let accent = #f00\ninclude \"rules.i\"\n").unwrap();
+        std::fs::write(dir.join("parts/rules.i"), "class \"firefox\" \"F\"\n").unwrap();
+
+        let loaded = load_statements(path.to_str().unwrap()).unwrap();
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+
+        let sources :Vec<&str> = loaded.sources.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(sources, [path.to_str().unwrap(), dir.join("parts/colors.i").to_str().unwrap(), dir.join("parts/rules.i").to_str().unwrap()]);
+
+        // variables from an include carry on after it
+        let rules :Vec<(usize, String, Option<String>)> = loaded.stmts.iter()
+            .map(|(origin, stmt)| (origin.source, stmt.rule().unwrap().1.pattern().to_string(), stmt.rule().unwrap().1.color.clone()))
+            .collect();
+        assert_eq!(rules, [(2, "firefox".to_string(), None), (0, "kitty".to_string(), Some("#f00".to_string()))]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_an_error() {
+        let path = config_path("cycle");
+        let dir = path.parent().unwrap();
+
+        std::fs::write(&path, "include \"a.i\"\nclass \"kitty\" \"K\"\n").unwrap();
+        std::fs::write(dir.join("a.i"), "class \"firefox\" \"F\"\n\ninclude \"config.i\"\n").unwrap();
+
+        let loaded = load_statements(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.stmts.len(), 2);
+        assert_eq!(loaded.errors.len(), 1);
+
+        let error = &loaded.errors[0];
+        let canonical_dir = dir.canonicalize().unwrap();
+        assert_eq!(error.file.as_deref(), dir.join("a.i").to_str());
+        assert_eq!((error.line, error.column), (3, 10));
+        assert_eq!(error.message, format!(
+            "include cycle: {} -> {} -> {}",
+            canonical_dir.join("config.i").display(), canonical_dir.join("a.i").display(), canonical_dir.join("config.i").display()
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_mistake_is_reported() {
+        let path = config_path("errors");
+        let dir = path.parent().unwrap();
+        let path_str = path.to_str().unwrap();
+
+        std::fs::write(&path, concat!(
+            "class \"firefox\" \"F\"\n",
+            "class \"kitty\"\n",
+            "title \"(unclosed\" \"T\"\n",
+            "default \"D\" colr #fff\n",
+            "\n",
+            "fmt \"{icon}\n",
+            "include \"part.i\"\n",
+            "bogus \"x\"\n"
+        )).unwrap();
+        std::fs::write(dir.join("part.i"), "desktop \"I\" label\n").unwrap();
+
+        let Err(ConfigError::Parse(_, errors)) = parse_config(path_str) else { panic!("the config has mistakes in it") };
+        let part = dir.join("part.i").display().to_string();
+        let found :Vec<(&str, usize, usize, &str)> = errors.iter()
+            .map(|e| (if e.file.as_deref() == Some(part.as_str()) { "part.i" } else { "config.i" }, e.line, e.column, e.message.as_str()))
+            .collect();
+
+        assert_eq!(found, [
+            ("config.i", 2, 14, "unexpected end of line"),
+            ("config.i", 3, 7, "invalid regex: unclosed group"),
+            ("config.i", 4, 13, "unknown keyword `colr`"),
+            ("config.i", 6, 5, "unterminated string"),
+            ("part.i", 1, 18, "unexpected end of line"),
+            ("config.i", 8, 1, "unknown keyword `bogus`")
+        ]);
+
+        assert_eq!(errors[1].render(path_str), format!(concat!(
+            "error: invalid regex: unclosed group\n",
+            " --> {}:3:7\n",
+            "  |\n",
+            "3 | title \"(unclosed\" \"T\"\n",
+            "  |       ^^^^^^^^^^^"
+        ), path_str));
+
+        // the file it's in wins over the one being checked
+        assert_eq!(errors[4].render(path_str), format!(concat!(
+            "error: unexpected end of line\n",
+            " --> {}:1:18\n",
+            "  |\n",
+            "1 | desktop \"I\" label\n",
+            "  |                  ^ expected a quoted string"
+        ), part));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::{load_statements, ConfigError, Statements};
+use crate::icons::Icon;
+use crate::parser::{quote, DesktopSetting, Stmt};
+use crate::toml_config::{is_toml, to_toml};
+
+// glyphs can go in bare, anything the lexer would read as something else needs quotes
+fn icon_text(icon: &Icon) -> String {
+    let text = icon.to_string();
+    let bare = text.chars().next().is_some_and(|c| !c.is_ascii()) && !text.contains(|c: char| c.is_whitespace() || c == '{' || c == '}');
+    if bare { text } else { quote(&text) }
+}
+
+// the icon and whatever goes after it, in the order the parser wants them
+fn icon_settings(icon: &Icon) -> String {
+    let mut out = icon_text(icon);
+    if let Some(f) = &icon.fallback { out += &format!(" fallback {}", quote(f)); }
+    if let Some(c) = &icon.color { out += &format!(" color {c}"); }
+    if let Some(c) = &icon.fcolor { out += &format!(" focused_color {c}"); }
+    if let Some(c) = &icon.ucolor { out += &format!(" urgent_color {c}"); }
+    if icon.reversed_class() == 'r' { out += " reversed"; }
+    out
+}
+
+fn statement(stmt: &Stmt) -> Option<String> {
+    let line = match (stmt, stmt.rule()) {
+        (_, Some((property, icon))) => format!("{} {} {}", property.keyword(), quote(icon.pattern()), icon_settings(icon)),
+        (Stmt::Compound(condition, icon), _) => format!("{condition} {}", icon_settings(icon)),
+        (Stmt::Default(icon), _) => format!("default {}", icon_settings(icon)),
+        (Stmt::Empty(icon), _) => format!("empty {}", icon_settings(icon)),
+        (Stmt::Desktop(name, DesktopSetting::Label(label)), _) => format!("desktop {} label {}", quote(name), quote(label)),
+        (Stmt::Desktop(name, DesktopSetting::Default(icon)), _) => format!("desktop {} default {}", quote(name), icon_settings(icon)),
+        (Stmt::Desktop(name, DesktopSetting::Empty(icon)), _) => format!("desktop {} empty {}", quote(name), icon_settings(icon)),
+        (Stmt::FmtBefore(f), _) => format!("before_fmt {}", quote(f)),
+        (Stmt::Fmt(f), _) => format!("fmt {}", quote(f)),
+        (Stmt::FmtAfter(f), _) => format!("after_fmt {}", quote(f)),
+        (Stmt::IconBefore(f), _) => format!("before_icon {}", quote(f)),
+        (Stmt::IconAfter(f), _) => format!("after_icon {}", quote(f)),
+        _ => return None
+    };
+
+    Some(line)
+}
+
+fn to_config(stmts: &[Stmt]) -> String {
+    stmts.iter().filter_map(statement).map(|s| s + "\n").collect()
+}
+
+// a .i config as toml or the other way around, printed so it can go wherever
+// comments don't survive, includes and variables get written out in full
+pub fn convert(path: &str) -> i32 {
+    let Statements { stmts, errors, .. } = match load_statements(path) {
+        Ok(loaded) => loaded,
+        Err(e) => { eprintln!("{e}"); return 1; }
+    };
+
+    if !errors.is_empty() {
+        eprintln!("{}", ConfigError::Parse(path.to_string(), errors));
+        return 1;
+    }
+
+    let stmts :Vec<Stmt> = stmts.into_iter().map(|(_, s)| s).collect();
+    match is_toml(path) {
+        true => print!("{}", to_config(&stmts)),
+        false => print!("{}", to_toml(&stmts))
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Lexer, Parser};
+    use crate::toml_config::parse_toml;
+
+    fn parse(config: &str) -> Vec<Stmt> {
+        let mut parser = Parser::new();
+        Lexer::new(config).map(|s| parser.parse(s.unwrap()).unwrap()).filter(|s| !matches!(s, Stmt::None)).collect()
+    }
+
+    fn parse_as_toml(config: &str) -> Vec<Stmt> {
+        let (stmts, errors) = parse_toml(config);
+        assert!(errors.is_empty(), "{errors:?}");
+        stmts.into_iter().map(|(_, _, s)| s).collect()
+    }
+
+    #[test]
+    fn i_to_toml_and_back() {
+        let config = concat!(
+            "fmt \"[{desktop}:{icon}:{project}] \\\\\"\n",
+            "default \"D\" fallback \"d\" color #fff focused_color #000 urgent_color #f00\n",
+            "empty 󰈹 color #111 reversed\n",
+            "desktop \"I\" label \"web \\\"1\\\"\"\n",
+            "title \"(?P<project>\\\\w+) - \\\"Code\\\"\" \"C\"\n",
+            "class \"a\\\\\\\\b\" \"two words\"\n",
+            "title \".*\\\\\\\\\" \"end\"\n",
+            "title \".*GitHub.*\" and not class \"kitty\" or class \"chromium\" \"GH\"\n",
+        );
+
+        let stmts = parse(config);
+        let toml = to_toml(&stmts);
+        let back = to_config(&parse_as_toml(&toml));
+
+        assert_eq!(back, to_config(&stmts));
+        assert_eq!(to_toml(&parse(&back)), toml);
+    }
+
+    #[test]
+    fn backslashes_survive() {
+        let toml = "[format]\nfmt = '{icon}\\'\n\n[[rule]]\nclass = 'a\\\\b'\nicon = \"A\"\n\n[[rule]]\ntitle = '.*\\\\'\nicon = \"T\"\n";
+        let config = to_config(&parse_as_toml(toml));
+
+        assert_eq!(config, "fmt \"{icon}\\\\\"\nclass \"a\\\\\\\\b\" \"A\"\ntitle \".*\\\\\\\\\" \"T\"\n");
+        assert_eq!(to_toml(&parse(&config)), toml);
+    }
+}
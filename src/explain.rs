@@ -0,0 +1,89 @@
+use bspc_rs::selectors::NodeSelector;
+use crate::backend::Desktop;
+use crate::config::read_config;
+use crate::icons::{Icon, Icons};
+use crate::parser::Stmt;
+use crate::window::{Atoms, KnownWindow, parse_window_id, render_desktop};
+
+pub enum Subject {
+    Window(String, String),
+    WindowId(String),
+    Node(String)
+}
+
+fn load_window(subject: Subject) -> Result<KnownWindow, String> {
+    let id = match subject {
+        Subject::Window(class, title) => return Ok(KnownWindow::new_known(0, class, title)),
+        Subject::WindowId(id) => parse_window_id(&id).ok_or(format!("invalid window id: {id}"))?,
+        Subject::Node(selector) => match bspc_rs::query::query_nodes(None, None, None, Some(NodeSelector(&selector))) {
+            Ok(nodes) if !nodes.is_empty() => nodes[0],
+            Ok(_) => return Err(format!("no node matches {selector}")),
+            Err(e) => return Err(format!("bspc error: {e}"))
+        }
+    };
+
+    let (x_conn, _) = xcb::Connection::connect(None).map_err(|e| format!("X error: {e:?}"))?;
+    let atoms = Atoms::intern_all(&x_conn).map_err(|e| format!("X error: {e:?}"))?;
+    Ok(KnownWindow::new(&x_conn, &atoms, &id))
+}
+
+fn describe_rule(stmt: &Stmt) -> String {
+    match (stmt, stmt.rule()) {
+        (_, Some((property, i))) => format!("{} \"{}\" {i}", property.keyword(), i.pattern()),
+        (Stmt::Compound(c, i), _) => format!("{c} {i}"),
+        _ => String::new()
+    }
+}
+
+fn describe_icon(icon: &Icon) -> String {
+    format!("icon: {icon}\ncolor: {}\nfocused_color: {}\nurgent_color: {}\nreversed: {}",
+        icon.color.as_deref().unwrap_or("(default)"),
+        icon.fcolor.as_deref().unwrap_or("(default)"),
+        icon.ucolor.as_deref().unwrap_or("(default)"),
+        if icon.reversed_class() == 'r' { "yes" } else { "no" })
+}
+
+// walks the rules the same way get_icon does, but says what happened on the way
+pub fn explain(path: &str, subject: Subject) -> i32 {
+    let mut icons = Icons::new();
+    if let Err(e) = read_config(&mut icons, path) {
+        eprintln!("{e}");
+        return 1;
+    }
+
+    let window = match load_window(subject) {
+        Ok(w) => w,
+        Err(e) => { eprintln!("{e}"); return 1; }
+    };
+
+    println!("class: {}\ninstance: {}\ntitle: {}\nrole: {}\ntype: {}", window.class, window.instance, window.title, window.role, window.window_type);
+    println!("process: {}\nchild processes: {}\n", window.process.join(" | "), window.child_processes.join(" | "));
+
+    let mut matched = None;
+    for (i, stmt) in icons.get_rules().iter().enumerate() {
+        let hit = Icons::rule_matches(stmt, &window).is_some();
+        println!("{:>3}. {:<40} {}", i + 1, describe_rule(stmt), if hit { "matched" } else { "no match" });
+        if hit { matched = Some(i + 1); break; }
+    }
+
+    let icon = icons.get_icon(&window);
+    match matched {
+        Some(rule) => println!("\nrule {rule} matched"),
+        None => println!("\nno rule matched, using default"),
+    }
+
+    println!("{}", describe_icon(icon.unwrap_or(icons.get_default())));
+
+    let mut captures :Vec<(String, String)> = icons.get_captures(&window).into_iter().collect();
+    captures.sort();
+    for (name, text) in captures {
+        println!("{{{name}}}: {text}");
+    }
+    println!();
+
+    let desktop = Desktop { id: 0, name: "desktop".to_string(), monitor: String::new(), windows: vec![window] };
+    println!("fmt: {}", render_desktop(&desktop, false, &icons));
+    println!("fmt (focused): {}", render_desktop(&desktop, true, &icons));
+
+    0
+}
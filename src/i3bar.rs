@@ -0,0 +1,73 @@
+use std::io::BufRead;
+use serde_json::{json, Value};
+use crate::backend::Desktop;
+use crate::bspwm::focus_desktop;
+use crate::icons::Icons;
+use crate::window::{desktop_colors, desktop_icon, render_desktop};
+
+// sent once before the first status line, the infinite array starts right after it
+pub const HEADER: &str = "{\"version\":1,\"click_events\":true}\n[";
+
+// the config uses polybar style colors (#rgb, #argb, #rrggbb, #aarrggbb),
+// i3bar only takes #rrggbb and #rrggbbaa
+fn i3bar_color(color: &str) -> String {
+    let hex = color.trim_start_matches('#');
+    let long :String = match hex.len() {
+        3 | 4 => hex.chars().flat_map(|c| [c, c]).collect(),
+        _ => hex.to_string()
+    };
+
+    match long.len() {
+        8 => format!("#{}{}", &long[2..], &long[..2]),
+        _ => format!("#{long}")
+    }
+}
+
+fn desktop_block(desktop: &Desktop, focused: bool, icons: &Icons) -> Value {
+    let icon = desktop_icon(desktop, icons);
+    let (color, fcolor) = desktop_colors(desktop, focused, icons);
+    let color = i3bar_color(if focused { &fcolor } else { &color });
+
+    let mut block = json!({
+        "full_text": render_desktop(desktop, focused, icons),
+        "name": "desktop",
+        "instance": desktop.name,
+        "urgent": desktop.is_urgent(),
+        "separator": false,
+    });
+
+    if icon.reversed_class() == 'r' {
+        block["background"] = json!(color);
+    } else {
+        block["color"] = json!(color);
+    }
+
+    block
+}
+
+// one element of the infinite array, before_fmt and after_fmt have no block to go into so they're left out
+pub fn render_i3bar(desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+    let blocks :Vec<Value> = desktops.iter().map(|d| desktop_block(d, Some(d.id) == focused, icons)).collect();
+    format!("{},", Value::Array(blocks))
+}
+
+// the bar writes click events back as another infinite array on our stdin
+pub fn thread_clicks() {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { return };
+        let event = line.trim().trim_start_matches(['[', ',']);
+        let Ok(event) = serde_json::from_str::<Value>(event) else { continue };
+
+        if event["name"] != "desktop" { continue; }
+        let Some(desktop) = event["instance"].as_str() else { continue };
+
+        let selector = match event["button"].as_u64() {
+            Some(1) => desktop,
+            Some(4) => "prev.local",
+            Some(5) => "next.local",
+            _ => continue
+        };
+
+        focus_desktop(selector);
+    }
+}
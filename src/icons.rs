@@ -1,12 +1,17 @@
+use std::collections::HashMap;
 use std::fmt::{ Display, Formatter };
 use regex::Regex;
-use crate::parser::Stmt;
+use crate::parser::{DesktopSetting, Stmt};
+use crate::condition::{named_captures, Property};
+use crate::window::KnownWindow;
 
 pub struct Icon {
-    icon: char,
+    icon: String,
+    pub fallback: Option<String>, // plain text for when the glyph can't be shown
     regex: Regex,
     pub color: Option<String>,
     pub fcolor: Option<String>,
+    pub ucolor: Option<String>, // for desktops with an urgent window
     reversed: bool
 }
 
@@ -17,43 +22,101 @@ impl Display for Icon {
 }
 
 impl Icon {
-    pub fn new(icon: char, regex: &str, color: Option<String>, fcolor: Option<String>, reversed: bool) -> Result<Self, std::io::Error> {
+    pub fn new(icon: String, regex: &str, color: Option<String>, fcolor: Option<String>, ucolor: Option<String>, reversed: bool, fallback: Option<String>) -> Result<Self, std::io::Error> {
         let regex = match Regex::new(&format!("^{}$", regex)) {
             Ok(r) => r,
-            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+            Err(e) => return Err(std::io::Error::other(e))
         };
 
-        Ok(Self { icon, regex, color, fcolor, reversed })
+        Ok(Self { icon, fallback, regex, color, fcolor, ucolor, reversed })
+    }
+
+    pub fn text(&self, use_fallback: bool) -> &str {
+        match &self.fallback {
+            Some(f) if use_fallback => f,
+            _ => &self.icon
+        }
     }
 
     pub fn matches(&self, str: &str) -> bool {
         self.regex.is_match(str)
     }
 
+    pub fn captures(&self, str: &str, out: &mut HashMap<String, String>) -> bool {
+        named_captures(&self.regex, str, out)
+    }
+
+    pub fn capture_names(&self) -> impl Iterator<Item = &str> {
+        self.regex.capture_names().flatten()
+    }
+
+    // the regex as written in the config
+    pub fn pattern(&self) -> &str {
+        let r = self.regex.as_str();
+        &r[1..r.len() - 1]
+    }
+
     pub fn reversed_class(&self) -> char {
         if self.reversed { return 'r' }
-        return ' '
+        ' '
     }
 }
 
+// what `desktop "<name>" ...` statements changed for one desktop
+#[derive(Default)]
+pub struct DesktopOverrides {
+    pub label: Option<String>,
+    pub default: Option<Icon>,
+    pub empty: Option<Icon>
+}
+
 pub struct Icons {
     empty: Icon,
     default: Icon,
+    desktops: HashMap<String, DesktopOverrides>,
     icons: Vec<Stmt>,
-    format: [String; 3]
+    format: [String; 3],
+    icon_format: [String; 2],
+    use_fallback: bool,
+    generation: u64, // bumped every time a reload goes through
+    error: Option<String> // why the last reload didn't
+}
+
+impl Default for Icons {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Icons {
     pub fn new() -> Self {
         let def_color = "#000".to_string();
         Self { 
-            empty: Icon::new('', " ", Some(def_color.clone()), Some(def_color.clone()), false).unwrap(),
-            default: Icon::new('', " ", Some(def_color.clone()), Some(def_color.clone()), false).unwrap(),
+            empty: Icon::new("".to_string(), " ", Some(def_color.clone()), Some(def_color.clone()), None, false, None).unwrap(),
+            default: Icon::new("".to_string(), " ", Some(def_color.clone()), Some(def_color.clone()), None, false, None).unwrap(),
+            desktops: HashMap::new(),
             icons: vec![],
             format: [ "[".to_string(), " {icon} ".to_string(), "]".to_string() ],
+            icon_format: [ "".to_string(), " ".to_string() ],
+            use_fallback: false,
+            generation: 0,
+            error: None,
         }
     }
 
+    pub fn get_rules(&self) -> &[Stmt] {
+        &self.icons
+    }
+
+    // whether any rule looks at `property`, some are expensive to read for every window
+    pub fn uses(&self, property: Property) -> bool {
+        self.icons.iter().any(|stmt| match (stmt, stmt.rule()) {
+            (_, Some((p, _))) => p == property,
+            (Stmt::Compound(c, _), _) => c.uses(property),
+            _ => false
+        })
+    }
+
     pub fn set_icon(&mut self, icon_stmt: Stmt) {
         self.icons.push(icon_stmt);
     }
@@ -67,16 +130,36 @@ impl Icons {
         self.empty = icon;
     }
 
-    pub fn get_icon(&self, class: &str, title: &str) -> Option<&Icon> {
-        for stmt in &self.icons {
-            match stmt {
-                Stmt::Class(i) => if i.matches(class) { return Some(i); }
-                Stmt::Title(i) => if i.matches(title) { return Some(i); }
-                _ => continue,
-            }
+    // the icon of a single rule, if it applies to the window
+    pub fn rule_matches<'a>(stmt: &'a Stmt, window: &KnownWindow) -> Option<&'a Icon> {
+        match (stmt, stmt.rule()) {
+            (_, Some((property, i))) if property.of(window).iter().any(|v| i.matches(v)) => Some(i),
+            (Stmt::Compound(c, i), _) if c.eval(window) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn get_icon(&self, window: &KnownWindow) -> Option<&Icon> {
+        self.icons.iter().find_map(|stmt| Self::rule_matches(stmt, window))
+    }
+
+    // named regex groups of the rule get_icon picked
+    pub fn get_captures(&self, window: &KnownWindow) -> HashMap<String, String> {
+        let mut captures = HashMap::new();
+        let stmt = self.icons.iter().find(|stmt| Self::rule_matches(stmt, window).is_some());
+
+        match stmt.map(|stmt| (stmt, stmt.rule())) {
+            Some((_, Some((property, i)))) => { property.of(window).iter().any(|v| i.captures(v, &mut captures)); }
+            Some((Stmt::Compound(c, _), _)) => c.captures(window, &mut captures),
+            _ => ()
         }
-        
-        None
+
+        captures
+    }
+
+    // every group name any rule has, so fmt can use them even when another rule matched
+    pub fn capture_names(&self) -> Vec<&str> {
+        self.icons.iter().flat_map(Stmt::capture_names).collect()
     }
 
     pub fn set_before(&mut self, s: String) {
@@ -91,6 +174,26 @@ impl Icons {
         self.format[2] = s;
     }
 
+    pub fn set_before_icon(&mut self, s: String) {
+        self.icon_format[0] = s;
+    }
+
+    pub fn set_after_icon(&mut self, s: String) {
+        self.icon_format[1] = s;
+    }
+
+    pub fn set_use_fallback(&mut self, use_fallback: bool) {
+        self.use_fallback = use_fallback;
+    }
+
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+    }
+
     pub fn get_default(&self) -> &Icon {
         &self.default
     }
@@ -99,6 +202,27 @@ impl Icons {
         &self.empty
     }
 
+    pub fn set_desktop(&mut self, name: String, setting: DesktopSetting) {
+        let overrides = self.desktops.entry(name).or_default();
+        match setting {
+            DesktopSetting::Label(l) => overrides.label = Some(l),
+            DesktopSetting::Default(i) => overrides.default = Some(i),
+            DesktopSetting::Empty(i) => overrides.empty = Some(i)
+        }
+    }
+
+    pub fn get_label<'a>(&'a self, desktop: &'a str) -> &'a str {
+        self.desktops.get(desktop).and_then(|d| d.label.as_deref()).unwrap_or(desktop)
+    }
+
+    pub fn get_default_for(&self, desktop: &str) -> &Icon {
+        self.desktops.get(desktop).and_then(|d| d.default.as_ref()).unwrap_or(&self.default)
+    }
+
+    pub fn get_empty_for(&self, desktop: &str) -> &Icon {
+        self.desktops.get(desktop).and_then(|d| d.empty.as_ref()).unwrap_or(&self.empty)
+    }
+
     pub fn get_before(&self) -> &str {
         &self.format[0]
     }
@@ -110,7 +234,24 @@ impl Icons {
     pub fn get_after(&self) -> &str {
         &self.format[2]
     }
-}
 
+    pub fn get_before_icon(&self) -> &str {
+        &self.icon_format[0]
+    }
 
+    pub fn get_after_icon(&self) -> &str {
+        &self.icon_format[1]
+    }
 
+    pub fn get_use_fallback(&self) -> bool {
+        self.use_fallback
+    }
+
+    pub fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
@@ -0,0 +1,48 @@
+use serde_json::{json, Value};
+use crate::backend::Desktop;
+use crate::icons::Icons;
+use crate::window::{desktop_icon, icon_colors, window_icon};
+
+fn desktop_json(desktop: &Desktop, focused: bool, icons: &Icons) -> Value {
+    let icon = desktop_icon(desktop, icons);
+    let (color, fcolor) = icon_colors(icon, icons);
+    let window = desktop.windows.first();
+
+    let windows :Vec<Value> = desktop.windows.iter().map(|w| json!({
+        "id": w.id,
+        "class": w.class,
+        "title": w.title,
+        "urgent": w.urgent,
+        "icon": window_icon(w, &desktop.name, icons).text(icons.get_use_fallback()),
+    })).collect();
+
+    json!({
+        "name": desktop.name,
+        "label": icons.get_label(&desktop.name),
+        "monitor": desktop.monitor,
+        "focused": focused,
+        "occupied": window.is_some(),
+        "urgent": desktop.is_urgent(),
+        "icon": icon.text(icons.get_use_fallback()),
+        "color": color,
+        "focused_color": fcolor,
+        "urgent_color": icon.ucolor.as_ref().or(icons.get_default().ucolor.as_ref()),
+        "reversed": icon.reversed_class() == 'r',
+        "window_class": window.map(|w| w.class.as_str()),
+        "window_title": window.map(|w| w.title.as_str()),
+        "captures": window.map(|w| icons.get_captures(w)).unwrap_or_default(),
+        "windows": windows,
+    })
+}
+
+// one json document per line, the raw data instead of a rendered fmt string
+pub fn render_json(desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+    let focused_name = desktops.iter().find(|d| Some(d.id) == focused).map(|d| d.name.as_str());
+    let desktops :Vec<Value> = desktops.iter().map(|d| desktop_json(d, Some(d.id) == focused, icons)).collect();
+
+    json!({
+        "focused": focused_name,
+        "desktops": desktops,
+        "config_error": icons.get_error(),
+    }).to_string()
+}
@@ -0,0 +1,47 @@
+use std::io::BufRead;
+use crate::backend::Desktop;
+use crate::bspwm::focus_desktop;
+use crate::icons::Icons;
+use crate::polybar::escape_action;
+use crate::window::{desktop_colors, desktop_icon, render_desktop};
+
+// lemonbar prints these back when an area is clicked, see thread_clicks
+const FOCUS: &str = "focus ";
+
+// same idea as polybar but reversed icons use lemonbar's own %{R} instead of a background color,
+// so whatever background the bar has ends up as the text color
+pub fn render_lemonbar(desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+    let mut string = format!("%{{A4:{FOCUS}prev.local:}}%{{A5:{FOCUS}next.local:}}{}", icons.get_before());
+
+    for desktop in desktops {
+        let is_focused = Some(desktop.id) == focused;
+        let icon = desktop_icon(desktop, icons);
+        let (color, fcolor) = desktop_colors(desktop, is_focused, icons);
+        let reversed = icon.reversed_class() == 'r';
+        let inner = render_desktop(desktop, is_focused, icons);
+
+        string += &format!("%{{A:{FOCUS}{}:}}%{{F{}}}", escape_action(&desktop.name), if is_focused { &fcolor } else { &color });
+        if reversed { string += "%{R}"; }
+
+        if is_focused {
+            string += &format!("%{{U{fcolor}}}%{{+u}}{inner}%{{-u}}%{{U-}}");
+        } else {
+            string += &inner;
+        }
+
+        if reversed { string += "%{R}"; }
+        string += "%{F-}%{A}";
+    }
+
+    string + icons.get_after() + "%{A}%{A}"
+}
+
+// stands in for the usual `lemonbar | sh`, anything that isn't one of ours gets ignored
+pub fn thread_clicks() {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else { return };
+        if let Some(selector) = line.trim_end().strip_prefix(FOCUS) {
+            focus_desktop(selector);
+        }
+    }
+}
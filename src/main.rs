@@ -1,4 +1,4 @@
-use std::sync::{ Mutex, Arc };
+use std::sync::{ Mutex, Arc, mpsc };
 use std::env::args;
 use std::thread;
 
@@ -7,52 +7,164 @@ pub mod parser;
 pub mod config;
 pub mod bspwm;
 pub mod icons;
+pub mod condition;
 pub mod window;
-//pub mod any_wm;
+pub mod process;
+pub mod backend;
+pub mod output;
+pub mod polybar;
+pub mod json;
+pub mod i3bar;
+pub mod lemonbar;
+pub mod check;
+pub mod explain;
+pub mod toml_config;
+pub mod convert;
+pub mod any_wm;
+pub mod scripted;
 
 
 use crate::icons::Icons;
-use crate::bspwm::thread_bspwm;
-//use any_wm::thread_any_wm;
-use crate::config::thread_config;
+use crate::backend::{thread_render, RenderMsg};
+use crate::bspwm::BspwmBackend;
+use crate::any_wm::EwmhBackend;
+use crate::scripted::ScriptedBackend;
+use crate::output::{Format, Output, Target};
+use crate::config::{read_config, thread_config};
 
 
 
 fn main() -> xcb::Result<()> {
     let icons :Arc<Mutex<Icons>> = Arc::new(Mutex::new(Icons::new()));
-    let mut args = args().skip(1);
-    let path = match args.next() {
+    let mut args = args().skip(1).peekable();
+
+    if args.peek().map(String::as_str) == Some("check") {
+        args.next();
+        match args.next() {
+            Some(path) => std::process::exit(check::check_config(&path)),
+            None => { println!("usage: check <config>"); std::process::exit(1) }
+        }
+    }
+
+    if args.peek().map(String::as_str) == Some("convert") {
+        args.next();
+        match args.next() {
+            Some(path) => std::process::exit(convert::convert(&path)),
+            None => { println!("usage: convert <config.i | config.toml>"); std::process::exit(1) }
+        }
+    }
+
+    // what lemonbar's stdout gets piped into instead of sh
+    if args.peek().map(String::as_str) == Some("lemonbar-clicks") {
+        lemonbar::thread_clicks();
+        return Ok(());
+    }
+
+    if args.peek().map(String::as_str) == Some("explain") {
+        args.next();
+        let rest :Vec<String> = args.collect();
+        let subject = match rest.get(1).map(String::as_str) {
+            Some("--window") if rest.len() == 3 => explain::Subject::WindowId(rest[2].clone()),
+            Some("--node") if rest.len() == 3 => explain::Subject::Node(rest[2].clone()),
+            Some(_) if rest.len() == 3 => explain::Subject::Window(rest[1].clone(), rest[2].clone()),
+            _ => { println!("usage: explain <config> <class> <title> | --window <id> | --node <selector>"); std::process::exit(1) }
+        };
+
+        std::process::exit(explain::explain(&rest[0], subject));
+    }
+
+    let mut wm = "bspwm".to_string();
+    let mut target = Target::Stdout;
+    let mut format = Format::Fmt;
+    let mut show_errors = false;
+    let mut use_fallback = false;
+    let mut positional :Vec<String> = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wm" => match args.next() {
+                Some(w) => wm = w,
+                None => { eprintln!("--wm needs a value (bspwm, ewmh or script:<path>)"); std::process::exit(1) }
+            },
+            "--monitors" => match args.next().map(|m| Target::from_arg(&m)) {
+                Some(Ok(t)) => target = t,
+                Some(Err(e)) => { eprintln!("{e}"); std::process::exit(1) }
+                None => { eprintln!("--monitors needs a value (- or a path with {{monitor}} in it)"); std::process::exit(1) }
+            },
+            "--format" => match args.next().map(|f| Format::from_arg(&f)) {
+                Some(Ok(f)) => format = f,
+                Some(Err(e)) => { eprintln!("{e}"); std::process::exit(1) }
+                None => { eprintln!("--format needs a value (fmt, polybar, json, i3bar or lemonbar)"); std::process::exit(1) }
+            },
+            "--show-config-errors" => show_errors = true,
+            "--fallback-icons" => use_fallback = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let path = match positional.next() {
         Some(p) => p,
-        None => { println!("Path to config not given"); return Ok(()) }
+        None => { eprintln!("Path to config not given"); std::process::exit(1) }
     };
 
-    let workspaces :Vec<String> = args.collect();
+    let workspaces :Vec<String> = positional.collect();
 
-    let icons_arc = icons.clone();
-    let config_thread_handle = thread::spawn(move || { match thread_config(icons_arc.clone(), &path) {
-        Ok(_) => (),
-        Err(e) => println!("config error: {:?}", e)
-    }});
+    // load the config before anything gets rendered, the config thread only handles reloads
+    if let Ok(mut icons) = icons.lock() {
+        icons.set_use_fallback(use_fallback);
+        if let Err(e) = read_config(&mut icons, &path) {
+            eprintln!("{e}");
+        }
+    }
+
+    let (render_tx, render_rx) = mpsc::channel::<RenderMsg>();
 
     let icons_arc = icons.clone();
-    let bspwm_thread_handle = thread::spawn(move || { match thread_bspwm(icons_arc.clone(), workspaces) {
+    let config_render_tx = render_tx.clone();
+    // never returns on its own, it goes away with the process once the wm is gone
+    thread::spawn(move || { match thread_config(icons_arc.clone(), &path, config_render_tx) {
         Ok(_) => (),
-        Err(e) => println!("error: {:?}", e)
+        Err(e) => eprintln!("config error: {:?}", e)
     }});
 
-/*    let bspwm_thread_handle = thread::spawn(move || { match thread_any_wm(icons_arc.clone(), workspaces) {
-        Ok(_) => (),
-        Err(e) => println!("error: {:?}", e),
-    }});
-*/
-    match config_thread_handle.join() {
-        Ok(_) => (),
-        Err(e) => println!("config error: {:?}", e),
+    // i3bar and swaybar send clicks back on stdin
+    if let Format::I3bar = format {
+        thread::spawn(i3bar::thread_clicks);
     }
 
+    let output = Output::new(target, format, show_errors);
+    let icons_arc = icons.clone();
+    let bspwm_thread_handle = match wm.as_str() {
+        "bspwm" => thread::spawn(move || { match BspwmBackend::new(workspaces).and_then(|b| thread_render(b, icons_arc.clone(), output, render_tx, render_rx)) {
+            Ok(_) => (),
+            Err(e) => eprintln!("error: {:?}", e)
+        }}),
+
+        "ewmh" => thread::spawn(move || { match EwmhBackend::new(workspaces).and_then(|b| thread_render(b, icons_arc.clone(), output, render_tx, render_rx)) {
+            Ok(_) => (),
+            Err(e) => eprintln!("error: {:?}", e),
+        }}),
+
+        w if w.starts_with("script:") => {
+            let script = match std::fs::read_to_string(&w["script:".len()..]).and_then(|s| ScriptedBackend::from_script(&s)) {
+                Ok(s) => s,
+                Err(e) => { eprintln!("script error: {:?}", e); std::process::exit(1) }
+            };
+
+            thread::spawn(move || { match thread_render(script, icons_arc.clone(), output, render_tx, render_rx) {
+                Ok(_) => (),
+                Err(e) => eprintln!("error: {:?}", e),
+            }})
+        }
+
+        _ => { eprintln!("unknown wm: {wm}"); std::process::exit(1) }
+    };
+
+    // the render thread is done once the backend runs out of events, a script or bspwm quitting
     match bspwm_thread_handle.join() {
         Ok(_) => (),
-        Err(e) => println!("error: {:?}", e),
+        Err(e) => eprintln!("error: {:?}", e),
     }
 
     Ok(())
@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use crate::backend::{Desktop, Workspaces};
+use crate::icons::Icons;
+use crate::window::render_icons;
+use crate::polybar::render_polybar;
+use crate::json::render_json;
+use crate::i3bar::{render_i3bar, HEADER};
+use crate::lemonbar::render_lemonbar;
+
+// how a set of desktops turns into a line
+pub enum Format {
+    Fmt,
+    Polybar,
+    Json,
+    I3bar,
+    Lemonbar
+}
+
+impl Format {
+    pub fn from_arg(arg: &str) -> Result<Self, std::io::Error> {
+        match arg {
+            "fmt" => Ok(Format::Fmt),
+            "polybar" => Ok(Format::Polybar),
+            "json" => Ok(Format::Json),
+            "i3bar" => Ok(Format::I3bar),
+            "lemonbar" => Ok(Format::Lemonbar),
+            _ => Err(std::io::Error::other(format!("unknown format: {arg}, expected fmt, polybar, json, i3bar or lemonbar")))
+        }
+    }
+
+    // written once to every output before the first line
+    fn header(&self) -> Option<&'static str> {
+        match self {
+            Format::I3bar => Some(HEADER),
+            _ => None
+        }
+    }
+}
+
+pub enum Target {
+    // one line with every desktop
+    Stdout,
+    // one line per monitor, prefixed with the monitor name
+    PrefixedStdout,
+    // one file or named pipe per monitor, {monitor} in the path gets replaced with the monitor name
+    Files(String)
+}
+
+impl Target {
+    pub fn from_arg(arg: &str) -> Result<Self, std::io::Error> {
+        if arg == "-" { return Ok(Target::PrefixedStdout); }
+        if !arg.contains("{monitor}") {
+            return Err(std::io::Error::other(format!("monitor output path needs a {{monitor}} in it: {arg}")));
+        }
+
+        Ok(Target::Files(arg.to_string()))
+    }
+}
+
+pub struct Output {
+    target: Target,
+    format: Format,
+    show_errors: bool,
+    files: HashMap<String, OutputFile>,
+    last: HashMap<String, String>,
+    started: HashSet<String>
+}
+
+struct OutputFile {
+    file: File,
+    fifo: bool // a regular file only ever holds the latest line, a fifo gets every line
+}
+
+fn open_output(path: &str) -> Result<OutputFile, std::io::Error> {
+    let fifo = std::fs::metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false);
+
+    // opening a fifo write-only blocks until someone reads it, and writing blocks once nobody has for a while,
+    // either would stall every other monitor
+    if fifo {
+        let file = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+        return Ok(OutputFile { file, fifo });
+    }
+
+    Ok(OutputFile { file: OpenOptions::new().write(true).create(true).truncate(true).open(path)?, fifo })
+}
+
+// the whole file is the latest line, with the header in front of it for whoever reads it fresh
+fn rewrite(file: &mut File, header: Option<&str>, line: &str) -> Result<(), std::io::Error> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    match header {
+        Some(h) => writeln!(file, "{h}\n{line}"),
+        None => writeln!(file, "{line}")
+    }
+}
+
+impl Output {
+    pub fn new(target: Target, format: Format, show_errors: bool) -> Self {
+        Self { target, format, show_errors, files: HashMap::new(), last: HashMap::new(), started: HashSet::new() }
+    }
+
+    fn render(&self, desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+        match icons.get_error() {
+            // json already carries the error in its own field
+            Some(e) if self.show_errors && !matches!(self.format, Format::Json) => match self.format {
+                // anything that isn't a block would kill the bar
+                Format::I3bar => format!("{},", serde_json::json!([{ "full_text": format!("config error: {e}"), "name": "error" }])),
+                _ => format!("config error: {e}")
+            },
+            _ => match self.format {
+                Format::Fmt => render_icons(desktops, focused, icons),
+                Format::Polybar => render_polybar(desktops, focused, icons),
+                Format::Json => render_json(desktops, focused, icons),
+                Format::I3bar => render_i3bar(desktops, focused, icons),
+                Format::Lemonbar => render_lemonbar(desktops, focused, icons)
+            }
+        }
+    }
+
+    // monitors in the order their first desktop shows up
+    fn by_monitor(workspaces: &Workspaces) -> Vec<(&str, Vec<&Desktop>)> {
+        let mut monitors :Vec<(&str, Vec<&Desktop>)> = vec![];
+
+        for desktop in &workspaces.desktops {
+            match monitors.iter_mut().find(|(m, _)| *m == desktop.monitor) {
+                Some((_, desktops)) => desktops.push(desktop),
+                None => monitors.push((&desktop.monitor, vec![desktop]))
+            }
+        }
+
+        monitors
+    }
+
+    // the format header with a trailing newline the first time an output is written to, nothing after that
+    fn header_for(&mut self, monitor: &str) -> String {
+        if !self.started.insert(monitor.to_string()) { return String::new(); }
+        self.format.header().map(|h| h.to_string() + "\n").unwrap_or_default()
+    }
+
+    pub fn write(&mut self, workspaces: &Workspaces, icons: &Icons) {
+        if let Target::Stdout = self.target {
+            let desktops :Vec<&Desktop> = workspaces.desktops.iter().collect();
+            let line = self.render(&desktops, workspaces.focused, icons);
+            println!("{}{line}", self.header_for(""));
+            return;
+        }
+
+        let mut monitors = Self::by_monitor(workspaces);
+
+        // a monitor that lost its last desktop still needs its bar cleared
+        for monitor in self.last.keys() {
+            if !monitors.iter().any(|(m, _)| m == monitor) { monitors.push((monitor, vec![])); }
+        }
+
+        let monitors :Vec<(String, Vec<&Desktop>)> = monitors.into_iter().map(|(m, d)| (m.to_string(), d)).collect();
+        for (monitor, desktops) in monitors {
+            let monitor = monitor.as_str();
+            let line = self.render(&desktops, workspaces.focused, icons);
+
+            // no point in waking up every bar when only one monitor changed
+            if self.last.get(monitor) == Some(&line) { continue; }
+            self.last.insert(monitor.to_string(), line.clone());
+
+            match &self.target {
+                Target::Stdout => unreachable!(),
+                Target::PrefixedStdout => {
+                    for header in self.header_for(monitor).lines() { println!("{monitor} {header}"); }
+                    println!("{monitor} {line}");
+                }
+                Target::Files(pattern) => {
+                    let path = pattern.replace("{monitor}", monitor);
+                    if !self.files.contains_key(monitor) {
+                        match open_output(&path) {
+                            Ok(f) => { self.files.insert(monitor.to_string(), f); }
+                            Err(e) => { eprintln!("output error: {path}: {e}"); continue; }
+                        }
+                    }
+
+                    let header = self.header_for(monitor);
+                    let out = self.files.get_mut(monitor).unwrap();
+                    let written = match out.fifo {
+                        // in one go, so a full pipe takes all of it or none of it
+                        true => out.file.write_all(format!("{header}{line}\n").as_bytes()),
+                        false => rewrite(&mut out.file, self.format.header(), &line)
+                    };
+
+                    match written {
+                        Ok(()) => (),
+                        // nobody's reading, the line goes and the next one gets another try
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            self.last.remove(monitor);
+                            self.started.remove(monitor);
+                        }
+                        Err(e) => {
+                            eprintln!("output error: {path}: {e}");
+                            // try opening it again next time, whoever reads it then needs the header again
+                            self.files.remove(monitor);
+                            self.started.remove(monitor);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::WmEvent;
+
+    fn output_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("bspwm-workspace-icons-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // one desktop on m1, renamed every time so every line is different
+    fn write_as(output: &mut Output, workspaces: &mut Workspaces, name: String) {
+        match workspaces.desktops.is_empty() {
+            false => workspaces.apply(WmEvent::DesktopRenamed { id: 1, name }),
+            true => {
+                workspaces.apply(WmEvent::DesktopAdded { id: 1, name });
+                workspaces.apply(WmEvent::DesktopTransferred { desktop: 1, monitor: "m1".to_string() });
+            }
+        }
+
+        output.write(workspaces, &Icons::new());
+    }
+
+    #[test]
+    fn a_file_only_has_the_latest_line() {
+        let dir = output_dir("file");
+        let mut output = Output::new(Target::Files(dir.join("{monitor}").to_str().unwrap().to_string()), Format::I3bar, false);
+        let mut workspaces = Workspaces::new();
+
+        write_as(&mut output, &mut workspaces, "first".to_string());
+        write_as(&mut output, &mut workspaces, "second".to_string());
+
+        let written = std::fs::read_to_string(dir.join("m1")).unwrap();
+        assert_eq!(written.lines().count(), 3, "{written}");
+        assert!(written.starts_with(HEADER) && written.contains("second") && !written.contains("first"), "{written}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_fifo_nobody_reads_doesnt_block() {
+        let dir = output_dir("fifo");
+        let fifo = std::ffi::CString::new(dir.join("m1").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+        let mut output = Output::new(Target::Files(dir.join("{monitor}").to_str().unwrap().to_string()), Format::Fmt, false);
+        let mut workspaces = Workspaces::new();
+
+        // well past what the pipe holds
+        for i in 0..10000 {
+            write_as(&mut output, &mut workspaces, format!("desktop-{i}"));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,183 +1,591 @@
+use std::collections::HashMap;
+use std::fmt::{ Display, Formatter };
 use crate::icons::Icon;
+use crate::condition::{Condition, Property};
 
 pub enum Stmt {
     Class(Icon),
+    Instance(Icon),
     Title(Icon),
+    Role(Icon),
+    Type(Icon),
+    Process(Icon),
+    ChildProcess(Icon),
+    Compound(Condition, Icon), // a rule looking at more than one thing
     Default(Icon),
     Empty(Icon),
+    Desktop(String, DesktopSetting),
     FmtBefore(String),
     Fmt(String),
     FmtAfter(String),
+    IconBefore(String),
+    IconAfter(String),
+    Include(String), // resolved by the config loader, never reaches Icons
     None
 }
 
+pub enum DesktopSetting {
+    Label(String), // shown as {desktop} instead of the name
+    Default(Icon),
+    Empty(Icon)
+}
+
+impl Stmt {
+    // the keyword and icon of the statements that match windows
+    pub fn rule(&self) -> Option<(Property, &Icon)> {
+        match self {
+            Stmt::Class(i) => Some((Property::Class, i)),
+            Stmt::Instance(i) => Some((Property::Instance, i)),
+            Stmt::Title(i) => Some((Property::Title, i)),
+            Stmt::Role(i) => Some((Property::Role, i)),
+            Stmt::Type(i) => Some((Property::Type, i)),
+            Stmt::Process(i) => Some((Property::Process, i)),
+            Stmt::ChildProcess(i) => Some((Property::ChildProcess, i)),
+            _ => None
+        }
+    }
+
+    // a single match stays a plain rule, anything more is a compound one
+    pub fn rule_from(condition: Condition, icon: Icon) -> Stmt {
+        let rule :fn(Icon) -> Stmt = match &condition {
+            Condition::Matches(Property::Class, _) => Stmt::Class,
+            Condition::Matches(Property::Instance, _) => Stmt::Instance,
+            Condition::Matches(Property::Title, _) => Stmt::Title,
+            Condition::Matches(Property::Role, _) => Stmt::Role,
+            Condition::Matches(Property::Type, _) => Stmt::Type,
+            Condition::Matches(Property::Process, _) => Stmt::Process,
+            Condition::Matches(Property::ChildProcess, _) => Stmt::ChildProcess,
+            _ => return Stmt::Compound(condition, icon)
+        };
+
+        rule(icon)
+    }
+
+    // named regex groups, they turn into fmt placeholders
+    pub fn capture_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        match (self, self.rule()) {
+            (_, Some((_, i))) => names.extend(i.capture_names()),
+            (Stmt::Compound(c, _), _) => c.capture_names(&mut names),
+            _ => ()
+        }
+
+        names
+    }
+}
+
+// the regex crate draws its own little diagram, only the last line is worth keeping
+pub fn regex_message(e: &regex::Error) -> String {
+    let e = e.to_string();
+    format!("invalid regex: {}", e.lines().last().unwrap_or_default().trim_start_matches("error: "))
+}
+
+// text as a config string, the lexer drops a backslash and keeps whatever comes after it
+pub fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
 #[derive(Debug, PartialEq, Clone)]
 enum Token {
     Class,
+    Instance,
     Title,
+    Role,
+    Type,
+    Process,
+    ChildProcess,
+    And,
+    Or,
+    Not,
     String(String),
-    Icon(char),
+    Icon(String),
     Default,
     Empty,
+    Desktop,
+    Label,
     Reversed,
+    Fallback,
     Before,
     Fmt,
     After,
+    BeforeIcon,
+    AfterIcon,
     Color(String),
     NormalColor,
     FocusedColor,
-    EOF
+    UrgentColor,
+    Let,
+    Equals,
+    Include,
+    OpenBrace,
+    CloseBrace,
+    Variable(String), // never reaches the parser, it gets swapped for the value
+    Ident(String), // a word that isn't a keyword, only good as a variable name
+    Eof
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Token::Class => write!(f, "`class`"),
+            Token::Instance => write!(f, "`instance`"),
+            Token::Title => write!(f, "`title`"),
+            Token::Role => write!(f, "`role`"),
+            Token::Type => write!(f, "`type`"),
+            Token::Process => write!(f, "`process`"),
+            Token::ChildProcess => write!(f, "`child_process`"),
+            Token::And => write!(f, "`and`"),
+            Token::Or => write!(f, "`or`"),
+            Token::Not => write!(f, "`not`"),
+            Token::String(s) => write!(f, "string \"{s}\""),
+            Token::Icon(c) => write!(f, "icon `{c}`"),
+            Token::Default => write!(f, "`default`"),
+            Token::Empty => write!(f, "`empty`"),
+            Token::Desktop => write!(f, "`desktop`"),
+            Token::Label => write!(f, "`label`"),
+            Token::Reversed => write!(f, "`reversed`"),
+            Token::Fallback => write!(f, "`fallback`"),
+            Token::Before => write!(f, "`before_fmt`"),
+            Token::Fmt => write!(f, "`fmt`"),
+            Token::After => write!(f, "`after_fmt`"),
+            Token::BeforeIcon => write!(f, "`before_icon`"),
+            Token::AfterIcon => write!(f, "`after_icon`"),
+            Token::Color(c) => write!(f, "color `{c}`"),
+            Token::NormalColor => write!(f, "`color`"),
+            Token::FocusedColor => write!(f, "`focused_color`"),
+            Token::UrgentColor => write!(f, "`urgent_color`"),
+            Token::Let => write!(f, "`let`"),
+            Token::Include => write!(f, "`include`"),
+            Token::Equals => write!(f, "`=`"),
+            Token::OpenBrace => write!(f, "`{{`"),
+            Token::CloseBrace => write!(f, "`}}`"),
+            Token::Variable(v) => write!(f, "`${v}`"),
+            Token::Ident(w) => write!(f, "`{w}`"),
+            Token::Eof => write!(f, "end of line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    pub snippet: String,
+    pub message: String,
+    pub expected: Option<String>,
+    pub file: Option<String> // which file of the config, when it isn't the one being rendered for
+}
+
+impl ParseError {
+    // points at `needle` if the statement on lines `first..=last` of `config` has it, or at its first line if not
+    pub fn at(config: &str, first: usize, last: usize, needle: Option<&str>, message: String) -> Self {
+        let lines :Vec<&str> = config.lines().collect();
+        let found = needle.and_then(|n| (first..=last).find_map(|l| lines.get(l - 1)?.find(n).map(|i| (l, i, n))));
+
+        let (line, column, len) = match found {
+            Some((l, i, n)) => (l, lines[l - 1][..i].chars().count(), n.chars().count()),
+            None => {
+                let snippet = lines.get(first - 1).copied().unwrap_or_default();
+                let trimmed = snippet.trim_start();
+                (first, snippet.chars().count() - trimmed.chars().count(), trimmed.trim_end().chars().count())
+            }
+        };
+
+        let snippet = lines.get(line - 1).copied().unwrap_or_default().to_string();
+        ParseError { line, column: column + 1, len, snippet, message, expected: None, file: None }
+    }
+
+    // rustc style, with the offending part of the line underlined
+    pub fn render(&self, path: &str) -> String {
+        self.render_as("error", path)
+    }
+
+    pub fn render_as(&self, level: &str, path: &str) -> String {
+        let path = self.file.as_deref().unwrap_or(path);
+        let number = self.line.to_string();
+        let pad = " ".repeat(number.len());
+        let mut out = format!("{level}: {}\n{pad}--> {path}:{}:{}\n{pad} |\n{number} | {}\n{pad} | {}{}",
+            self.message, self.line, self.column, self.snippet,
+            " ".repeat(self.column.saturating_sub(1)), "^".repeat(self.len.max(1)));
+
+        if let Some(expected) = &self.expected {
+            out += &format!(" expected {expected}");
+        }
+
+        out
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)?;
+        if let Some(expected) = &self.expected {
+            write!(f, ", expected {expected}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// a token and where it starts, columns count chars like the rest of the error output
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    line: usize,
+    column: usize,
+    len: usize
+}
+
+// the tokens of one statement, which can go over several lines
+#[derive(Default)]
+pub struct Tokens {
+    tokens: Vec<Spanned>,
+    pub first_line: usize,
+    pub last_line: usize,
+    snippets: Vec<String> // the lines from first_line to last_line, for error messages
+}
+
+impl Tokens {
+    // past the last token is where the statement ended
+    fn span(&self, index: usize) -> Spanned {
+        match (self.tokens.get(index), self.tokens.last()) {
+            (Some(t), _) => t.clone(),
+            (None, Some(last)) => Spanned { token: Token::Eof, line: last.line, column: last.column + last.len, len: 0 },
+            (None, None) => Spanned { token: Token::Eof, line: self.first_line, column: 1, len: 0 }
+        }
+    }
+
+    fn snippet(&self, line: usize) -> String {
+        self.snippets.get(line.saturating_sub(self.first_line)).cloned().unwrap_or_default()
+    }
 }
 
+// splits a whole file into statements, a newline ends one unless it's inside `{ }` or right after a `\`
 pub struct Lexer {
-    text: String,
-    pos: usize
+    chars: Vec<char>,
+    lines: Vec<String>,
+    pos: usize,
+    line: usize,
+    line_start: usize,
+    token_start: usize,
+    open: Vec<(usize, usize)> // line and column of every `{` that isn't closed yet
 }
 
 impl Lexer {
-    pub fn new() -> Self {
-        Self { text: "".to_string(), pos: 0 }
+    pub fn new(text: &str) -> Self {
+        Self {
+            chars: text.chars().collect(),
+            lines: text.lines().map(str::to_string).collect(),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            token_start: 0,
+            open: vec![]
+        }
     }
 
-    pub fn feed_next_line(&mut self, line: &str) {
-        self.text = line.to_string();
-        self.pos = 0;
+    fn snippet(&self, line: usize) -> String {
+        self.lines.get(line - 1).cloned().unwrap_or_default()
+    }
+
+    // points at the token being read
+    fn error(&self, message: String, expected: Option<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.token_start - self.line_start + 1,
+            len: self.pos.saturating_sub(self.token_start),
+            snippet: self.snippet(self.line),
+            message,
+            expected,
+            file: None
+        }
     }
 
     fn get_current_char(&self) -> Option<char> {
-        self.text.chars().nth(self.pos)
+        self.chars.get(self.pos).copied()
     }
 
     fn peek(&self) -> Option<char> {
-        self.text.chars().nth(self.pos+1)
+        self.chars.get(self.pos + 1).copied()
     }
 
     fn advance(&mut self) {
+        if self.get_current_char() == Some('\n') {
+            self.line += 1;
+            self.line_start = self.pos + 1;
+        }
+
         self.pos += 1;
     }
 
-    fn skip_whitespace(&mut self) {
+    // a `\` with nothing but whitespace after it on the line
+    fn at_continuation(&self) -> bool {
+        self.get_current_char() == Some('\\')
+            && self.chars[self.pos + 1..].iter().take_while(|c| **c != '\n').all(|c| c.is_whitespace())
+    }
+
+    // the first word of a line inside braces being one of these means the `{` was never closed,
+    // nothing that goes inside braces starts with them
+    fn at_statement(&self) -> bool {
+        const STARTS :[&str; 18] = [
+            "default", "empty", "desktop", "let", "include", "class", "instance", "title", "role", "type",
+            "process", "child_process", "not", "before_fmt", "fmt", "after_fmt", "before_icon", "after_icon"
+        ];
+
+        let word :String = self.chars[self.pos..].iter().take_while(|c| !c.is_whitespace() && **c != '{' && **c != '}').collect();
+        STARTS.contains(&word.as_str())
+    }
+
+    // whitespace, comments and line continuations, newlines too while inside braces
+    fn skip_blank(&mut self) {
         while let Some(c) = self.get_current_char() {
-            if !c.is_whitespace() { break; }
-            self.advance();
+            if c == '#' && self.peek() == Some('#') {
+                self.skip_comment();
+            } else if self.at_continuation() {
+                while self.get_current_char().is_some_and(|c| c != '\n') { self.advance(); }
+                self.advance();
+            } else if c.is_whitespace() && (c != '\n' || !self.open.is_empty()) {
+                self.advance();
+            } else {
+                break;
+            }
         }
     }
 
+    // up to the end of the line, the newline itself still ends the statement
     fn skip_comment(&mut self) {
-        self.advance();
         while let Some(c) = self.get_current_char() {
             if c == '\n' { break; }
             self.advance();
         }
-
-        self.advance();
     }
 
-    fn color(&mut self) -> Token {
+    fn word(&mut self) -> String {
         let mut res = String::new();
         while let Some(cur_char) = self.get_current_char() {
+            if cur_char.is_whitespace() || cur_char == '{' || cur_char == '}' { break; }
             self.advance();
-            if cur_char.is_whitespace() { break; }
             res.push(cur_char);
         }
 
-        Token::Color(res)
+        res
+    }
+
+    fn color(&mut self) -> Token {
+        Token::Color(self.word())
+    }
+
+    // everything up to the next whitespace, so variation selectors, zwj sequences and the like stay in one piece
+    fn icon(&mut self) -> Token {
+        Token::Icon(self.word())
     }
 
-    fn string(&mut self) -> Token {
+    fn string(&mut self) -> Result<Token, ParseError> {
         let mut res = String::new();
-        let mut last_char = ' ';
+        let mut escaped = false;
         self.advance();
 
+        // a backslash keeps whatever comes after it, itself included
         while let Some(cur_char) = self.get_current_char() {
+            if cur_char == '\n' { break; }
             self.advance();
-            if cur_char == '\\' && last_char != '\\' { last_char = cur_char; continue; }
-            if cur_char == '"' && last_char != '\\' { break; }
-            last_char = cur_char;
+            if escaped { escaped = false; res.push(cur_char); continue; }
+            if cur_char == '\\' { escaped = true; continue; }
+            if cur_char == '"' { return Ok(Token::String(res)); }
             res.push(cur_char);
         }
 
-        Token::String(res)
+        Err(self.error("unterminated string".to_string(), Some("a closing `\"`".to_string())))
     }
 
-    fn id(&mut self) -> Result<Token, std::io::Error> {
-        let mut res = String::new();
-        while let Some(cur_char) = self.get_current_char() {
-            if cur_char.is_whitespace() { break; }
-            res.push(cur_char);
-            self.advance();
-        }
+    fn id(&mut self) -> Result<Token, ParseError> {
+        let res = self.word();
 
         match res.as_str() {
             "default" => Ok(Token::Default),
             "empty" => Ok(Token::Empty),
+            "desktop" => Ok(Token::Desktop),
+            "label" => Ok(Token::Label),
             "title" => Ok(Token::Title),
             "class" => Ok(Token::Class),
+            "instance" => Ok(Token::Instance),
+            "role" => Ok(Token::Role),
+            "type" => Ok(Token::Type),
+            "process" => Ok(Token::Process),
+            "child_process" => Ok(Token::ChildProcess),
+            "and" => Ok(Token::And),
+            "or" => Ok(Token::Or),
+            "not" => Ok(Token::Not),
             "reversed" => Ok(Token::Reversed),
+            "fallback" => Ok(Token::Fallback),
             "before_fmt" => Ok(Token::Before),
             "fmt" => Ok(Token::Fmt),
             "after_fmt" => Ok(Token::After),
+            "before_icon" => Ok(Token::BeforeIcon),
+            "after_icon" => Ok(Token::AfterIcon),
             "color" => Ok(Token::NormalColor),
             "focused_color" => Ok(Token::FocusedColor),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, "unknown token"))
+            "urgent_color" => Ok(Token::UrgentColor),
+            "let" => Ok(Token::Let),
+            "include" => Ok(Token::Include),
+            "=" => Ok(Token::Equals),
+            _ => match res.strip_prefix('$') {
+                Some(name) => Ok(Token::Variable(name.to_string())),
+                None => Ok(Token::Ident(res))
+            }
         }
     }
 
-    fn next_token(&mut self) -> Result<Token, std::io::Error> {
-        self.skip_whitespace();
-        while let Some(cur_char) = self.get_current_char() {
+    // called with whitespace already skipped and something to read
+    fn next_token(&mut self, cur_char: char) -> Result<Token, ParseError> {
+        self.token_start = self.pos;
 
-            if cur_char == '#' && self.peek() == Some('#') {
-                self.skip_comment();
-                self.skip_whitespace();
-                continue;
+        match cur_char {
+            '"' => self.string(),
+            '#' => Ok(self.color()),
+            '{' => {
+                self.open.push((self.line, self.pos - self.line_start + 1));
+                self.advance();
+                Ok(Token::OpenBrace)
             }
-
-            if cur_char == '"' {
-                return Ok(self.string())
+            '}' => {
+                self.advance();
+                match self.open.pop() {
+                    Some(_) => Ok(Token::CloseBrace),
+                    None => Err(self.error("unmatched `}`".to_string(), None))
+                }
             }
+            c if c.is_ascii() => self.id(),
+            _ => Ok(self.icon())
+        }
+    }
 
-            if cur_char == '#' {
-                return Ok(self.color())
+    // the next statement, or the first thing wrong with it
+    fn next_statement(&mut self) -> Option<Result<Tokens, ParseError>> {
+        // blank lines and comments in between
+        loop {
+            self.skip_blank();
+            match self.get_current_char() {
+                Some('\n') => self.advance(),
+                Some(_) => break,
+                None => return None
             }
+        }
+
+        let first_line = self.line;
+        let mut tokens = vec![];
+        let mut error = None;
 
-            if cur_char.is_ascii() {
-                return self.id()
+        loop {
+            let line = self.line;
+            self.skip_blank();
+            let cur_char = match self.get_current_char() {
+                Some('\n') | None if self.open.is_empty() => break,
+                Some(c) if self.open.is_empty() || self.line == line || !self.at_statement() => c,
+                _ => {
+                    let (line, column) = self.open[0];
+                    self.open.clear();
+                    error.get_or_insert(ParseError {
+                        line, column, len: 1, snippet: self.snippet(line),
+                        message: "unclosed `{`".to_string(), expected: Some("a closing `}`".to_string()), file: None
+                    });
+                    break;
+                }
+            };
+
+            // keep going after a bad token so the next statement starts in the right place
+            match self.next_token(cur_char) {
+                Ok(token) => tokens.push(Spanned { token, line: self.line, column: self.token_start - self.line_start + 1, len: self.pos - self.token_start }),
+                Err(e) => { error.get_or_insert(e); }
             }
-            
-            self.advance();
-            return Ok(Token::Icon(cur_char));
         }
 
-        return Ok(Token::EOF);
+        if let Some(e) = error { return Some(Err(e)); }
+
+        let last_line = tokens.last().map(|t: &Spanned| t.line).unwrap_or(first_line);
+        let snippets = (first_line..=last_line).map(|l| self.snippet(l)).collect();
+        Some(Ok(Tokens { tokens, first_line, last_line, snippets }))
+    }
+}
+
+impl Iterator for Lexer {
+    type Item = Result<Tokens, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_statement()
     }
 }
 
+// one parser can go through several files, variables are kept from one statement to the next
 pub struct Parser {
-    lexer: Lexer,
-    current_token: Token
+    statement: Tokens,
+    current: usize,
+    current_token: Token,
+    variables: HashMap<String, Token> // from `let`, a color or a string
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Parser {
-    pub fn new(lexer: Lexer) -> Self {
-        Self { lexer, current_token: Token::EOF }
+    pub fn new() -> Self {
+        Self { statement: Tokens::default(), current: 0, current_token: Token::Eof, variables: HashMap::new() }
+    }
+
+    // points at the current token
+    fn error(&self, message: String, expected: Option<String>) -> ParseError {
+        let span = self.statement.span(self.current);
+        ParseError {
+            line: span.line,
+            column: span.column,
+            len: span.len,
+            snippet: self.statement.snippet(span.line),
+            message,
+            expected,
+            file: None
+        }
     }
 
-    pub fn feed_next_line(&mut self, line :&str) {
-        self.lexer.feed_next_line(line);
-        self.current_token = self.lexer.next_token().unwrap();
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        self.current += 1;
+        self.read_token()
+    }
+
+    // the current token with variables already filled in
+    fn read_token(&self) -> Result<Token, ParseError> {
+        match self.statement.span(self.current).token {
+            Token::Variable(name) => match self.variables.get(&name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(self.error(format!("undefined variable `${name}`"), None))
+            },
+            t => Ok(t)
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        if let Token::Ident(word) = &self.current_token {
+            return self.error(format!("unknown keyword `{word}`"), None);
+        }
+
+        self.error(format!("unexpected {}", self.current_token), Some(expected.to_string()))
     }
 
-    fn eat(&mut self, expected_token: Token) -> Result<(), std::io::Error> {
+    fn eat(&mut self, expected_token: Token) -> Result<(), ParseError> {
         if self.current_token != expected_token {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unexpected token eat: {:?}", &self.current_token)))
+            return Err(self.unexpected(&expected_token.to_string()))
         }
 
-        self.current_token = self.lexer.next_token()?;
+        self.current_token = self.next_token()?;
         Ok(())
     }
 
-    fn reversed(&mut self) -> Result<bool, std::io::Error> {
+    fn reversed(&mut self) -> Result<bool, ParseError> {
         let old_token = self.current_token.clone();
         if let Token::Reversed = old_token {
             self.eat(Token::Reversed)?;
@@ -187,135 +595,458 @@ impl Parser {
         }
     }
 
-    fn color(&mut self) -> Result<Option<String>, std::io::Error> {
+    fn color(&mut self) -> Result<Option<String>, ParseError> {
         let old_token = self.current_token.clone();
 
         if let Token::Color(c) = old_token {
             self.eat(Token::Color(c.clone()))?;
             Ok(Some(c))
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unexpected token color: {:?}", old_token)))
+            Err(self.unexpected("a color like `#fff`"))
         }
     }
 
-    fn color_focused(&mut self) -> Result<Option<String>, std::io::Error> {
+    fn color_focused(&mut self) -> Result<Option<String>, ParseError> {
         let old_token = self.current_token.clone();
 
         if let Token::FocusedColor = old_token {
             self.eat(Token::FocusedColor)?;
             Ok(self.color()?)
         } else {
-            return Ok(None);
+            Ok(None)
         }
     }
 
+    fn color_urgent(&mut self) -> Result<Option<String>, ParseError> {
+        let old_token = self.current_token.clone();
 
-    fn color_normal(&mut self) -> Result<Option<String>, std::io::Error> {
+        if let Token::UrgentColor = old_token {
+            self.eat(Token::UrgentColor)?;
+            Ok(self.color()?)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn color_normal(&mut self) -> Result<Option<String>, ParseError> {
         let old_token = self.current_token.clone();
         if let Token::NormalColor = old_token {
             self.eat(Token::NormalColor)?;
             Ok(self.color()?)
         } else {
-            return Ok(None);
+            Ok(None)
         }
     }
 
-    fn icon(&mut self) -> Result<char, std::io::Error> {
+    // either a glyph or a quoted string, for text labels like "FF"
+    fn icon(&mut self) -> Result<String, ParseError> {
         let old_token = self.current_token.clone();
 
-        if let Token::Icon(i) = old_token {
-            self.eat(Token::Icon(i))?;
-            Ok(i)
+        match old_token {
+            Token::Icon(i) => { self.eat(Token::Icon(i.clone()))?; Ok(i) }
+            Token::String(s) => { self.eat(Token::String(s.clone()))?; Ok(s) }
+            _ => Err(self.unexpected("an icon"))
+        }
+    }
+
+    fn fallback(&mut self) -> Result<Option<String>, ParseError> {
+        let old_token = self.current_token.clone();
+        if let Token::Fallback = old_token {
+            self.eat(Token::Fallback)?;
+            Ok(Some(self.string()?))
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unexpected token icon: {:?}", old_token)))
+            Ok(None)
         }
     }
 
-    fn string(&mut self) -> Result<String, std::io::Error> {
+    fn string(&mut self) -> Result<String, ParseError> {
         let old_token = self.current_token.clone();
         if let Token::String(r) = old_token {
             self.eat(Token::String(r.to_string()))?;
             Ok(r)
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unexpected token regex: {:?}", old_token)))
+            Err(self.unexpected("a quoted string"))
         }
     }
 
-    fn icon_statement(&mut self) -> Result<Icon, std::io::Error> {
-        let regex = self.string()?;
-        let icon = self.icon()?;
-        let color = self.color_normal()?;
-        let fcolor = self.color_focused()?;
-        let reversed = self.reversed()?;
+    // `class "firefox"`, the regex gets compiled right away so errors can point at it
+    fn condition_match(&mut self) -> Result<Condition, ParseError> {
+        let property = match self.current_token {
+            Token::Class => Property::Class,
+            Token::Instance => Property::Instance,
+            Token::Title => Property::Title,
+            Token::Role => Property::Role,
+            Token::Type => Property::Type,
+            Token::Process => Property::Process,
+            Token::ChildProcess => Property::ChildProcess,
+            _ => return Err(self.unexpected("`class`, `instance`, `title`, `role`, `type`, `process` or `child_process`"))
+        };
+        self.eat(self.current_token.clone())?;
 
-        Ok(Icon::new(icon, &regex, color, fcolor, reversed)?)
+        let regex_error = self.error(String::new(), None);
+        let pattern = self.string()?;
+
+        Condition::new(property, &pattern).map_err(|e| ParseError { message: regex_message(&e), ..regex_error })
     }
 
-    fn class_statement(&mut self) -> Result<Stmt, std::io::Error> {
-        use Token::*;
+    fn condition_not(&mut self) -> Result<Condition, ParseError> {
+        if self.current_token == Token::Not {
+            self.eat(Token::Not)?;
+            return Ok(Condition::Not(Box::new(self.condition_not()?)));
+        }
 
-        self.eat(Class)?;
-        Ok(Stmt::Class(self.icon_statement()?))
+        self.condition_match()
     }
 
-    fn title_statement(&mut self) -> Result<Stmt, std::io::Error> {
-        use Token::*;
+    fn condition_and(&mut self) -> Result<Condition, ParseError> {
+        let mut condition = self.condition_not()?;
+        while self.current_token == Token::And {
+            self.eat(Token::And)?;
+            condition = Condition::And(Box::new(condition), Box::new(self.condition_not()?));
+        }
 
-        self.eat(Title)?;
-        Ok(Stmt::Title(self.icon_statement()?))
+        Ok(condition)
     }
 
-    fn default_statement(&mut self) -> Result<Stmt, std::io::Error>  {
-        self.eat(Token::Default)?;
+    fn condition(&mut self) -> Result<Condition, ParseError> {
+        let mut condition = self.condition_and()?;
+        while self.current_token == Token::Or {
+            self.eat(Token::Or)?;
+            condition = Condition::Or(Box::new(condition), Box::new(self.condition_and()?));
+        }
+
+        Ok(condition)
+    }
+
+    fn rule_statement(&mut self) -> Result<Stmt, ParseError> {
+        let condition = self.condition()?;
+        // the pattern already compiled once as part of the condition
+        let icon = self.icon_settings(condition.pattern().unwrap_or_default())?;
+        Ok(Stmt::rule_from(condition, icon))
+    }
+
+    // the icon and everything that goes with it, wrapping it all in `{ }` lets it go over several lines
+    fn icon_settings(&mut self, pattern: &str) -> Result<Icon, ParseError> {
+        let braced = self.current_token == Token::OpenBrace;
+        if braced { self.eat(Token::OpenBrace)?; }
+
         let icon = self.icon()?;
+        let fallback = self.fallback()?;
         let color = self.color_normal()?;
         let fcolor = self.color_focused()?;
+        let ucolor = self.color_urgent()?;
         let reversed = self.reversed()?;
 
-        Ok(Stmt::Default(Icon::new(icon, "", color, fcolor, reversed)?))
+        if braced { self.eat(Token::CloseBrace)?; }
+        Ok(Icon::new(icon, pattern, color, fcolor, ucolor, reversed, fallback).unwrap())
+    }
+
+    // an icon that doesn't come with a rule, for default, empty and their per desktop versions
+    fn plain_icon(&mut self) -> Result<Icon, ParseError> {
+        self.icon_settings("")
+    }
+
+    fn default_statement(&mut self) -> Result<Stmt, ParseError>  {
+        self.eat(Token::Default)?;
+        Ok(Stmt::Default(self.plain_icon()?))
     }
 
-    fn empty_statement(&mut self) -> Result<Stmt, std::io::Error>  {
+    fn empty_statement(&mut self) -> Result<Stmt, ParseError>  {
         self.eat(Token::Empty)?;
-        let icon = self.icon()?;
-        let color = self.color_normal()?;
-        let fcolor = self.color_focused()?;
-        let reversed = self.reversed()?;
+        Ok(Stmt::Empty(self.plain_icon()?))
+    }
+
+    // `desktop "I" label "web"`, one setting per statement
+    fn desktop_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.eat(Token::Desktop)?;
+        let name = self.string()?;
+
+        let setting = match self.current_token {
+            Token::Label => { self.eat(Token::Label)?; DesktopSetting::Label(self.string()?) }
+            Token::Default => { self.eat(Token::Default)?; DesktopSetting::Default(self.plain_icon()?) }
+            Token::Empty => { self.eat(Token::Empty)?; DesktopSetting::Empty(self.plain_icon()?) }
+            _ => return Err(self.unexpected("`label`, `default` or `empty`"))
+        };
+
+        Ok(Stmt::Desktop(name, setting))
+    }
+
+    // `let name = #hex` or `let name = "text"`, used later as $name
+    fn let_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.eat(Token::Let)?;
 
-        Ok(Stmt::Empty(Icon::new(icon, "", color, fcolor, reversed)?))
+        let name = match &self.current_token {
+            Token::Ident(name) => name.clone(),
+            _ => return Err(self.error(format!("unexpected {}", self.current_token), Some("a variable name".to_string())))
+        };
+        self.eat(Token::Ident(name.clone()))?;
+        self.eat(Token::Equals)?;
+
+        let value = match &self.current_token {
+            Token::Color(_) | Token::String(_) => self.current_token.clone(),
+            _ => return Err(self.unexpected("a color or a quoted string"))
+        };
+        self.eat(value.clone())?;
+
+        self.variables.insert(name, value);
+        Ok(Stmt::None)
+    }
+
+    fn include_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.eat(Token::Include)?;
+        Ok(Stmt::Include(self.string()?))
     }
 
-    fn before_statement(&mut self) -> Result<Stmt, std::io::Error> {
+    fn before_statement(&mut self) -> Result<Stmt, ParseError> {
         self.eat(Token::Before)?;
         Ok(Stmt::FmtBefore(self.string()?))
     }
 
-    fn fmt_statement(&mut self) -> Result<Stmt, std::io::Error> {
+    fn fmt_statement(&mut self) -> Result<Stmt, ParseError> {
         self.eat(Token::Fmt)?;
         Ok(Stmt::Fmt(self.string()?))
     }
 
-    fn after_statement(&mut self) -> Result<Stmt, std::io::Error> {
+    fn after_statement(&mut self) -> Result<Stmt, ParseError> {
         self.eat(Token::After)?;
         Ok(Stmt::FmtAfter(self.string()?))
     }
 
-    pub fn parse(&mut self) -> Result<Stmt, std::io::Error> {
+    fn before_icon_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.eat(Token::BeforeIcon)?;
+        Ok(Stmt::IconBefore(self.string()?))
+    }
+
+    fn after_icon_statement(&mut self) -> Result<Stmt, ParseError> {
+        self.eat(Token::AfterIcon)?;
+        Ok(Stmt::IconAfter(self.string()?))
+    }
+
+    fn start(&mut self, statement: Tokens) -> Result<(), ParseError> {
+        self.statement = statement;
+        self.current = 0;
+        self.current_token = self.read_token()?;
+        Ok(())
+    }
+
+    // just the part of a rule before the icon, for formats that write everything else their own way
+    pub fn parse_condition(&mut self, text: &str) -> Result<Condition, ParseError> {
+        self.start(Lexer::new(text).next().transpose()?.unwrap_or_default())?;
+        let condition = self.condition()?;
+
+        if self.current_token != Token::Eof {
+            return Err(self.unexpected("`and`, `or` or the end of the rule"));
+        }
+
+        Ok(condition)
+    }
+
+    pub fn parse(&mut self, statement: Tokens) -> Result<Stmt, ParseError> {
         use Token::*;
 
-        return match &self.current_token {
+        self.start(statement)?;
+
+        let stmt = match &self.current_token {
             Default => self.default_statement(),
             Empty => self.empty_statement(),
-            Class => self.class_statement(),
-            Title => self.title_statement(),
+            Desktop => self.desktop_statement(),
+            Let => self.let_statement(),
+            Include => self.include_statement(),
+            Class | Instance | Title | Role | Type | Process | ChildProcess | Not => self.rule_statement(),
             Before => self.before_statement(),
             Fmt => self.fmt_statement(),
             After => self.after_statement(),
-            EOF => Ok(Stmt::None),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unexpected token parse: {:?}", &self.current_token)))
+            BeforeIcon => self.before_icon_statement(),
+            AfterIcon => self.after_icon_statement(),
+            Eof => Ok(Stmt::None),
+            _ => Err(self.unexpected("a statement like `class`, `title`, `default`, `empty` or `fmt`"))
+        }?;
+
+        // one statement at a time, anything left over is a mistake
+        if self.current_token != Eof {
+            return Err(self.unexpected("end of line"));
         }
+
+        Ok(stmt)
     }
 
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::KnownWindow;
+
+    // one line per statement, errors as `line:column message`
+    fn parse(config: &str) -> Vec<String> {
+        let mut parser = Parser::new();
+        let describe = |stmt: Stmt| match (&stmt, stmt.rule()) {
+            (_, Some((property, icon))) => format!("{} {} {} {:?}", property.keyword(), icon.pattern(), icon, icon.color),
+            (Stmt::Default(icon), _) => format!("default {icon} {:?} {:?}", icon.color, icon.fcolor),
+            (Stmt::Empty(icon), _) => format!("empty {icon}"),
+            (Stmt::Fmt(f), _) => format!("fmt {f}"),
+            (Stmt::None, _) => "nothing".to_string(),
+            _ => "something else".to_string()
+        };
+
+        Lexer::new(config)
+            .map(|s| s.and_then(|s| parser.parse(s)).map_or_else(|e| format!("{}:{} {}", e.line, e.column, e.message), describe))
+            .collect()
+    }
+
+    fn lines(config: &str) -> Vec<(usize, usize)> {
+        Lexer::new(config).map(|s| s.map_or((0, 0), |s| (s.first_line, s.last_line))).collect()
+    }
+
+    #[test]
+    fn backslash_continues_the_line() {
+        let config = "class \"firefox\" \\\n    \"F\" \\  \n    color #fff\ntitle \"x\" \"T\"\n";
+
+        assert_eq!(parse(config), ["class firefox F Some(\"#fff\")", "title x T None"]);
+        assert_eq!(lines(config), [(1, 3), (4, 4)]);
+    }
+
+    #[test]
+    fn braces_go_over_lines() {
+        let config = "default {\n    \"D\"\n\n    color #fff\n    focused_color #000\n}\nempty \"E\"\n";
+
+        assert_eq!(parse(config), ["default D Some(\"#fff\") Some(\"#000\")", "empty E"]);
+        assert_eq!(lines(config), [(1, 6), (7, 7)]);
+    }
+
+    #[test]
+    fn comments_after_a_statement() {
+        let config = "## the browser\nclass \"firefox\" \"F\" color #fff ## a color, then a comment\nfmt \"{icon}\"##right after\n";
+
+        assert_eq!(parse(config), ["class firefox F Some(\"#fff\")", "fmt {icon}"]);
+    }
+
+    #[test]
+    fn an_unterminated_string_ends_with_its_line() {
+        let config = "class \"firefox\" \"F\ntitle \"x\" \"T\"\n";
+
+        assert_eq!(parse(config), ["1:17 unterminated string", "title x T None"]);
+    }
+
+    #[test]
+    fn an_unclosed_brace_ends_at_the_next_statement() {
+        let config = "default {\n    \"D\" color #fff\n\nclass \"kitty\" \"K\"\ndefault { \"D\"\nempty \"E\"\n";
+
+        assert_eq!(parse(config), ["1:9 unclosed `{`", "class kitty K None", "5:9 unclosed `{`", "empty E"]);
+    }
+
+    #[test]
+    fn an_unmatched_brace_is_only_its_own_statement() {
+        let config = "class \"firefox\" \"F\" }\nempty \"E\"\n";
+
+        assert_eq!(parse(config), ["1:21 unmatched `}`", "empty E"]);
+    }
+
+    #[test]
+    fn variables_get_filled_in() {
+        let config = concat!(
+            "let accent = #f00\n",
+            "let browser = \"firefox\"\n",
+            "class $browser \"F\" color $accent\n",
+            "let accent = #0f0\n",
+            "title \".*\" $browser color $accent\n"
+        );
+
+        assert_eq!(parse(config), ["nothing", "nothing", "class firefox F Some(\"#f00\")", "nothing", "title .* firefox Some(\"#0f0\")"]);
+    }
+
+    #[test]
+    fn an_undefined_variable_points_at_itself() {
+        let config = "let accent = #f00\nclass \"kitty\" \"K\" color $acent\nclass \"firefox\" \"F\" color $accent\n";
+        assert_eq!(parse(config), ["nothing", "2:25 undefined variable `$acent`", "class firefox F Some(\"#f00\")"]);
+
+        let tokens = Lexer::new("default {\n    \"D\" color $nope\n}").next().unwrap().unwrap();
+        let Err(error) = Parser::new().parse(tokens) else { panic!("$nope isn't defined") };
+        assert_eq!((error.line, error.column, error.len), (2, 15, 5));
+        assert_eq!(error.snippet, "    \"D\" color $nope");
+    }
+
+    #[test]
+    fn a_variable_is_a_color_or_a_string() {
+        assert_eq!(parse("let x = kitty\n"), ["1:9 unknown keyword `kitty`"]);
+        assert_eq!(parse("let = #fff\n"), ["1:5 unexpected `=`"]);
+    }
+
+    #[test]
+    fn the_caret_lines_up_past_line_nine() {
+        let config = format!("{}class \"kitty\" \"K\" color #fff\n", "\n".repeat(11));
+        let error = ParseError::at(&config, 12, 12, Some("#fff"), "unknown color".to_string());
+
+        assert_eq!(error.render_as("warning", "config.i"), concat!(
+            "warning: unknown color\n",
+            "  --> config.i:12:25\n",
+            "   |\n",
+            "12 | class \"kitty\" \"K\" color #fff\n",
+            "   |                         ^^^^"
+        ));
+    }
+
+    // the tree with every and, or and not in parentheses
+    fn shape(condition: &Condition) -> String {
+        match condition {
+            Condition::Matches(property, _) => format!("{}:{}", property.keyword(), condition.pattern().unwrap()),
+            Condition::Not(c) => format!("(not {})", shape(c)),
+            Condition::And(a, b) => format!("({} and {})", shape(a), shape(b)),
+            Condition::Or(a, b) => format!("({} or {})", shape(a), shape(b))
+        }
+    }
+
+    fn condition(text: &str) -> Condition {
+        Parser::new().parse_condition(text).unwrap()
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_than_or() {
+        assert_eq!(shape(&condition("class \"a\" or class \"b\" and not title \"c\"")), "(class:a or (class:b and (not title:c)))");
+        assert_eq!(shape(&condition("not class \"a\" and class \"b\" or title \"c\" and title \"d\"")), "(((not class:a) and class:b) or (title:c and title:d))");
+        assert_eq!(shape(&condition("class \"a\" and class \"b\" and class \"c\"")), "((class:a and class:b) and class:c)");
+        assert_eq!(shape(&condition("class \"a\" or class \"b\" or class \"c\"")), "((class:a or class:b) or class:c)");
+        assert_eq!(shape(&condition("not not class \"a\"")), "(not (not class:a))");
+    }
+
+    #[test]
+    fn conditions_match_windows() {
+        let github = KnownWindow::new_known(1, "firefox".to_string(), "rust - GitHub".to_string());
+        let private = KnownWindow::new_known(2, "firefox".to_string(), "rust - GitHub - Private".to_string());
+        let kitty = KnownWindow::new_known(3, "kitty".to_string(), "rust - GitHub".to_string());
+
+        // (GitHub and not Private) or kitty
+        let c = condition("title \".*GitHub.*\" and not title \".*Private\" or class \"kitty\"");
+        assert!(c.eval(&github));
+        assert!(!c.eval(&private));
+        assert!(c.eval(&kitty));
+
+        // (firefox and not Private) or (kitty and nope)
+        let c = condition("class \"firefox\" and not title \".*Private\" or class \"kitty\" and title \"nope\"");
+        assert!(c.eval(&github));
+        assert!(!c.eval(&private));
+        assert!(!c.eval(&kitty));
+
+        // the whole value has to match, not just part of it
+        assert!(!condition("class \"fire\"").eval(&github));
+    }
+
+    #[test]
+    fn only_what_made_it_true_is_captured() {
+        let window = KnownWindow::new_known(1, "firefox".to_string(), "rust - GitHub".to_string());
+        let captures = |text: &str| {
+            let mut out = HashMap::new();
+            condition(text).captures(&window, &mut out);
+            let mut out :Vec<(String, String)> = out.into_iter().collect();
+            out.sort();
+            out
+        };
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+
+        assert_eq!(captures("title \"(?P<repo>\\\\w+) - GitHub\" and class \"(?P<browser>.*)\""), [pair("browser", "firefox"), pair("repo", "rust")]);
+        assert_eq!(captures("title \"(?P<repo>\\\\w+) - GitHub\" or class \"(?P<browser>.*)\""), [pair("repo", "rust")]);
+        assert_eq!(captures("title \"(?P<repo>\\\\w+) - GitLab\" or class \"(?P<browser>.*)\""), [pair("browser", "firefox")]);
+        assert_eq!(captures("class \"(?P<browser>.*)\" and not title \"(?P<repo>\\\\w+) - GitLab\""), [pair("browser", "firefox")]);
+    }
+}
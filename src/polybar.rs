@@ -0,0 +1,59 @@
+use crate::backend::Desktop;
+use crate::icons::Icons;
+use crate::window::{desktop_colors, desktop_icon, render_desktop};
+
+// polybar splits action tags on ':' so those need escaping in the command
+pub fn escape_action(s: &str) -> String {
+    s.replace(':', "\\:")
+}
+
+// polybar hands click commands to sh, so a desktop name has to go in as one word and nothing more
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// every desktop gets wrapped in a click action and colored with its icon's colors,
+// the fmt string is still used for what goes inside
+pub fn render_polybar(desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+    let mut string = format!("%{{A4:bspc desktop -f prev.local:}}%{{A5:bspc desktop -f next.local:}}{}", icons.get_before());
+
+    for desktop in desktops {
+        let is_focused = Some(desktop.id) == focused;
+        let icon = desktop_icon(desktop, icons);
+        let (color, fcolor) = desktop_colors(desktop, is_focused, icons);
+        let inner = render_desktop(desktop, is_focused, icons);
+
+        string += &format!("%{{A1:bspc desktop -f {}:}}", escape_action(&shell_quote(&desktop.name)));
+
+        if icon.reversed_class() == 'r' {
+            string += &format!("%{{B{}}}", if is_focused { &fcolor } else { &color });
+        } else {
+            string += &format!("%{{F{}}}", if is_focused { &fcolor } else { &color });
+        }
+
+        if is_focused {
+            string += &format!("%{{u{fcolor}}}%{{+u}}{inner}%{{-u}}");
+        } else {
+            string += &inner;
+        }
+
+        string += if icon.reversed_class() == 'r' { "%{B-}" } else { "%{F-}" };
+        string += "%{A}";
+    }
+
+    string + icons.get_after() + "%{A}%{A}"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_one_word() {
+        assert_eq!(shell_quote("web"), "'web'");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$(rm -rf ~); `x`"), "'$(rm -rf ~); `x`'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(escape_action(&shell_quote("a:b")), "'a\\:b'");
+    }
+}
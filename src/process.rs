@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const PROC_ROOT: &str = "/proc";
+
+// walking the whole process table on every title change adds up, so it only happens when a rule needs it
+static WANT_CHILDREN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_want_children(want: bool) {
+    WANT_CHILDREN.store(want, Ordering::Relaxed);
+}
+
+pub fn want_children() -> bool {
+    WANT_CHILDREN.load(Ordering::Relaxed)
+}
+
+// comm and cmdline of one process, what `process` rules get matched against
+fn describe(root: &Path, pid: u32) -> Vec<String> {
+    let dir = root.join(pid.to_string());
+    let mut names = vec![];
+
+    if let Ok(comm) = fs::read_to_string(dir.join("comm")) {
+        names.push(comm.trim_end_matches('\n').to_string());
+    }
+
+    // arguments are separated by NULs, spaces are what anyone would write in a rule
+    if let Ok(cmdline) = fs::read(dir.join("cmdline")) {
+        let cmdline = String::from_utf8_lossy(&cmdline).trim_end_matches('\0').replace('\0', " ");
+        if !cmdline.is_empty() { names.push(cmdline); }
+    }
+
+    names
+}
+
+// pid -> children, from the ppid field of every /proc/<pid>/stat
+fn children_by_parent(root: &Path) -> HashMap<u32, Vec<u32>> {
+    let mut children :HashMap<u32, Vec<u32>> = HashMap::new();
+    let Ok(entries) = fs::read_dir(root) else { return children };
+
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) else { continue };
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else { continue };
+
+        // comm is in parentheses and can have spaces or parentheses of its own, so start after the last one
+        let ppid = stat.rfind(')').and_then(|i| stat[i + 1..].split_whitespace().nth(1)).and_then(|p| p.parse::<u32>().ok());
+        if let Some(ppid) = ppid {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    children
+}
+
+// (the process itself, everything it started and everything those started), for kitty running nvim and the like
+// without `children` the second half stays empty and only the process itself gets read
+pub fn read_process(root: &Path, pid: u32, children: bool) -> (Vec<String>, Vec<String>) {
+    let own = describe(root, pid);
+    if !children { return (own, vec![]); }
+
+    let tree = children_by_parent(root);
+
+    let mut descendants = vec![];
+    let mut seen = HashSet::from([pid]);
+    let mut queue = tree.get(&pid).cloned().unwrap_or_default();
+    while let Some(child) = queue.pop() {
+        if !seen.insert(child) { continue; } // only a made up /proc could loop, but still
+        descendants.extend(describe(root, child));
+        if let Some(c) = tree.get(&child) { queue.extend(c); }
+    }
+
+    (own, descendants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // a made up /proc with just comm, cmdline and stat for each pid
+    fn fake_proc(name: &str, processes: &[(u32, &str, &[u8], u32)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("bspwm-workspace-icons-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        for (pid, comm, cmdline, ppid) in processes {
+            let dir = root.join(pid.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("comm"), format!("{comm}\n")).unwrap();
+            fs::write(dir.join("cmdline"), cmdline).unwrap();
+            fs::write(dir.join("stat"), format!("{pid} ({comm}) S {ppid} {pid} {pid} 0 -1")).unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn reads_the_process_and_everything_below_it() {
+        let root = fake_proc("tree", &[
+            (100, "kitty", b"kitty\0--single-instance\0", 1),
+            (200, "weird ) (name", b"zsh\0", 100),
+            (300, "nvim", b"nvim\0notes.md\0", 200),
+            (400, "firefox", b"firefox\0", 1)
+        ]);
+
+        let (own, descendants) = read_process(&root, 100, true);
+        assert_eq!(own, ["kitty", "kitty --single-instance"]);
+        assert_eq!(descendants, ["weird ) (name", "zsh", "nvim", "nvim notes.md"]);
+
+        let (own, descendants) = read_process(&root, 100, false);
+        assert_eq!(own, ["kitty", "kitty --single-instance"]);
+        assert!(descendants.is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn a_process_that_went_away_has_nothing() {
+        let root = fake_proc("gone", &[(100, "kitty", b"kitty\0", 1)]);
+        assert_eq!(read_process(&root, 555, true), (vec![], vec![]));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
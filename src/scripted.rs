@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use crate::backend::{Backend, WmEvent};
+use crate::window::{KnownWindow, parse_window_id};
+
+// plays back a fixed list of event batches, handy for trying out configs and fmt strings without a wm
+//
+// script format, one batch per line, words separated by whitespace, titles take the rest of the line:
+//   desktop_add <name>
+//   desktop_remove <name>
+//   desktop_focus <name>
+//   desktop_rename <old> <new>
+//   desktop_swap <name> <name>
+//   desktop_transfer <name> <monitor>
+//   window_add <desktop> <id> <class> <title>
+//   window_focus <id>
+//   window_move <id> <desktop>
+//   window_remove <id>
+//   window_retitle <id> <class> <title>
+//   window_urgent <id> on|off
+// several events can share a batch when separated by ';', lines starting with ## are comments
+// a desktop is the first one that has that name at that point of the script, or %<n> for the n-th one added
+// since names can repeat
+pub struct ScriptedBackend {
+    batches: VecDeque<Vec<WmEvent>>
+}
+
+fn word<'a>(words: &mut impl Iterator<Item = &'a str>, line: &str) -> Result<String, std::io::Error> {
+    words.next().map(|w| w.to_string()).ok_or_else(|| std::io::Error::other(format!("missing argument in: {line}")))
+}
+
+fn parse_id(s: &str) -> Result<u32, std::io::Error> {
+    parse_window_id(s).ok_or_else(|| std::io::Error::other(format!("invalid window id: {s}")))
+}
+
+// every desktop the script added, its id is its place in here plus one
+#[derive(Default)]
+struct Desktops(Vec<Option<String>>); // None once removed
+
+impl Desktops {
+    // the id of the desktop the next word is
+    fn next<'a>(&self, words: &mut impl Iterator<Item = &'a str>, line: &str) -> Result<u32, std::io::Error> {
+        let desktop = word(words, line)?;
+        let found = match desktop.strip_prefix('%') {
+            Some(n) => n.parse::<usize>().ok().filter(|n| (1..=self.0.len()).contains(n)),
+            None => self.0.iter().position(|d| d.as_ref() == Some(&desktop)).map(|i| i + 1)
+        };
+
+        found.map(|id| id as u32).ok_or_else(|| std::io::Error::other(format!("unknown desktop {desktop} in: {line}")))
+    }
+}
+
+fn parse_event(line: &str, desktops: &mut Desktops) -> Result<WmEvent, std::io::Error> {
+    let mut words = line.split_whitespace();
+
+    let event = match word(&mut words, line)?.as_str() {
+        "desktop_add" => {
+            let name = word(&mut words, line)?;
+            desktops.0.push(Some(name.clone()));
+            WmEvent::DesktopAdded { id: desktops.0.len() as u32, name }
+        }
+        "desktop_remove" => {
+            let id = desktops.next(&mut words, line)?;
+            desktops.0[id as usize - 1] = None;
+            WmEvent::DesktopRemoved(id)
+        }
+        "desktop_focus" => WmEvent::DesktopFocused(desktops.next(&mut words, line)?),
+        "desktop_rename" => {
+            let id = desktops.next(&mut words, line)?;
+            let name = word(&mut words, line)?;
+            desktops.0[id as usize - 1] = Some(name.clone());
+            WmEvent::DesktopRenamed { id, name }
+        }
+        "desktop_swap" => WmEvent::DesktopsSwapped(desktops.next(&mut words, line)?, desktops.next(&mut words, line)?),
+        "desktop_transfer" => WmEvent::DesktopTransferred { desktop: desktops.next(&mut words, line)?, monitor: word(&mut words, line)? },
+        "window_add" => {
+            let desktop = desktops.next(&mut words, line)?;
+            let id = parse_id(&word(&mut words, line)?)?;
+            let class = word(&mut words, line)?;
+            let title = words.collect::<Vec<_>>().join(" ");
+            WmEvent::WindowAdded { desktop, window: KnownWindow::new_known(id, class, title) }
+        }
+        "window_focus" => WmEvent::WindowFocused(parse_id(&word(&mut words, line)?)?),
+        "window_move" => WmEvent::WindowMoved { id: parse_id(&word(&mut words, line)?)?, desktop: desktops.next(&mut words, line)? },
+        "window_remove" => WmEvent::WindowRemoved(parse_id(&word(&mut words, line)?)?),
+        "window_retitle" => {
+            let id = parse_id(&word(&mut words, line)?)?;
+            let class = word(&mut words, line)?;
+            let title = words.collect::<Vec<_>>().join(" ");
+            WmEvent::WindowRetitled(KnownWindow::new_known(id, class, title))
+        }
+        "window_urgent" => {
+            let id = parse_id(&word(&mut words, line)?)?;
+            let urgent = match word(&mut words, line)?.as_str() {
+                "on" => true,
+                "off" => false,
+                s => return Err(std::io::Error::other(format!("expected on or off, got {s} in: {line}")))
+            };
+            WmEvent::WindowUrgent { id, urgent }
+        }
+        e => return Err(std::io::Error::other(format!("unknown event: {e}")))
+    };
+
+    Ok(event)
+}
+
+impl ScriptedBackend {
+    pub fn new(batches: Vec<Vec<WmEvent>>) -> Self {
+        Self { batches: batches.into() }
+    }
+
+    pub fn from_script(script: &str) -> Result<Self, std::io::Error> {
+        let mut batches = vec![];
+        let mut desktops = Desktops::default();
+
+        for line in script.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("##") { continue; }
+
+            let mut batch = vec![];
+            for event in line.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+                batch.push(parse_event(event, &mut desktops)?);
+            }
+            batches.push(batch);
+        }
+
+        Ok(Self::new(batches))
+    }
+}
+
+impl Backend for ScriptedBackend {
+    fn poll(&mut self) -> xcb::Result<Option<Vec<WmEvent>>> {
+        Ok(self.batches.pop_front())
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
+use crate::condition::{Condition, Property};
+use crate::icons::Icon;
+use crate::parser::{regex_message, DesktopSetting, ParseError, Parser, Stmt};
+
+// the same config as a .i file, only the sections are fixed so the order of everything but the rules is too
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Spanned<FormatTable>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<Spanned<IconTable>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    empty: Option<Spanned<IconTable>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    desktop: BTreeMap<String, DesktopTable>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rule: Vec<Spanned<RuleTable>>
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct FormatTable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fmt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    before_icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after_icon: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IconTable {
+    icon: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    focused_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgent_color: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    reversed: bool
+}
+
+// spanned one by one, `[desktop.I]` itself doesn't have to be written out and toml can't say where it is then
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DesktopTable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<Spanned<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default: Option<Spanned<IconTable>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    empty: Option<Spanned<IconTable>>
+}
+
+// one property to match, or `match` with a whole condition in .i syntax for and, or and not
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RuleTable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    window_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    child_process: Option<String>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    icon: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    focused_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgent_color: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    reversed: bool
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+// spans only mean something when reading
+fn unspanned<T>(value: T) -> Spanned<T> {
+    Spanned::new(0..0, value)
+}
+
+pub fn is_toml(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|e| e == "toml")
+}
+
+impl IconTable {
+    fn from_icon(icon: &Icon) -> Self {
+        IconTable {
+            icon: icon.to_string(),
+            fallback: icon.fallback.clone(),
+            color: icon.color.clone(),
+            focused_color: icon.fcolor.clone(),
+            urgent_color: icon.ucolor.clone(),
+            reversed: icon.reversed_class() == 'r'
+        }
+    }
+
+    fn to_icon(&self) -> Icon {
+        Icon::new(self.icon.clone(), "", self.color.clone(), self.focused_color.clone(), self.urgent_color.clone(), self.reversed, self.fallback.clone()).unwrap()
+    }
+}
+
+impl RuleTable {
+    fn properties(&self) -> [(Property, &Option<String>); 7] {
+        [
+            (Property::Class, &self.class),
+            (Property::Instance, &self.instance),
+            (Property::Title, &self.title),
+            (Property::Role, &self.role),
+            (Property::Type, &self.window_type),
+            (Property::Process, &self.process),
+            (Property::ChildProcess, &self.child_process)
+        ]
+    }
+
+    fn from_stmt(stmt: &Stmt) -> Option<Self> {
+        let (mut rule, icon) = match (stmt, stmt.rule()) {
+            (_, Some((property, icon))) => {
+                let mut rule = RuleTable::default();
+                let pattern = Some(icon.pattern().to_string());
+                match property {
+                    Property::Class => rule.class = pattern,
+                    Property::Instance => rule.instance = pattern,
+                    Property::Title => rule.title = pattern,
+                    Property::Role => rule.role = pattern,
+                    Property::Type => rule.window_type = pattern,
+                    Property::Process => rule.process = pattern,
+                    Property::ChildProcess => rule.child_process = pattern
+                }
+                (rule, icon)
+            }
+            (Stmt::Compound(condition, icon), _) => (RuleTable { condition: Some(condition.to_string()), ..Default::default() }, icon),
+            _ => return None
+        };
+
+        let IconTable { icon, fallback, color, focused_color, urgent_color, reversed } = IconTable::from_icon(icon);
+        rule.icon = icon;
+        rule.fallback = fallback;
+        rule.color = color;
+        rule.focused_color = focused_color;
+        rule.urgent_color = urgent_color;
+        rule.reversed = reversed;
+        Some(rule)
+    }
+
+    // the message and the part of the rule to point at when it's no good
+    fn to_stmt(&self, parser: &mut Parser) -> Result<Stmt, (String, Option<String>)> {
+        let mut given = self.properties().into_iter().filter_map(|(p, v)| v.as_ref().map(|v| (p, v)));
+
+        let condition = match (given.next(), given.next(), &self.condition) {
+            (Some((property, pattern)), None, None) => Condition::new(property, pattern).map_err(|e| (regex_message(&e), Some(pattern.clone())))?,
+            (None, None, Some(condition)) => parser.parse_condition(condition).map_err(|e| (e.message, Some(condition.clone())))?,
+            _ => return Err(("a rule needs exactly one of class, instance, title, role, type, process, child_process or match".to_string(), None))
+        };
+
+        let icon = Icon::new(self.icon.clone(), condition.pattern().unwrap_or_default(), self.color.clone(),
+            self.focused_color.clone(), self.urgent_color.clone(), self.reversed, self.fallback.clone()).unwrap();
+        Ok(Stmt::rule_from(condition, icon))
+    }
+}
+
+// 1 based line of a byte offset
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+fn lines_of<T>(text: &str, spanned: &Spanned<T>) -> (usize, usize) {
+    let span = spanned.span();
+    (line_of(text, span.start), line_of(text, span.end.saturating_sub(1).max(span.start)))
+}
+
+// toml's own errors know the byte range that's wrong
+fn syntax_error(text: &str, e: &toml::de::Error) -> ParseError {
+    let span = e.span().unwrap_or(0..0);
+    let line = line_of(text, span.start);
+    let line_start = text[..span.start.min(text.len())].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let snippet = text.lines().nth(line - 1).unwrap_or_default().to_string();
+    let column = text[line_start..span.start.min(text.len())].chars().count() + 1;
+    let len = text[span.start.min(text.len())..span.end.min(text.len())].lines().next().unwrap_or_default().chars().count();
+
+    // the message has what was expected on a line of its own
+    let mut message = e.message().lines();
+    let expected = message.clone().nth(1).and_then(|l| l.strip_prefix("expected ")).map(str::to_string);
+
+    ParseError { line, column, len, snippet, message: message.next().unwrap_or_default().to_string(), expected, file: None }
+}
+
+// the statements of a toml config with the lines they're on, and what was wrong with the ones that couldn't be read
+pub fn parse_toml(text: &str) -> (Vec<(usize, usize, Stmt)>, Vec<ParseError>) {
+    let config :ConfigFile = match toml::from_str(text) {
+        Ok(c) => c,
+        Err(e) => return (vec![], vec![syntax_error(text, &e)])
+    };
+
+    let mut stmts = vec![];
+    let mut errors = vec![];
+
+    if let Some(format) = &config.format {
+        let (first, last) = lines_of(text, format);
+        let FormatTable { before, fmt, after, before_icon, after_icon } = format.get_ref();
+        let settings = [
+            (before, Stmt::FmtBefore as fn(String) -> Stmt), (fmt, Stmt::Fmt), (after, Stmt::FmtAfter),
+            (before_icon, Stmt::IconBefore), (after_icon, Stmt::IconAfter)
+        ];
+
+        for (value, stmt) in settings {
+            if let Some(value) = value { stmts.push((first, last, stmt(value.clone()))); }
+        }
+    }
+
+    for (table, stmt) in [(&config.default, Stmt::Default as fn(Icon) -> Stmt), (&config.empty, Stmt::Empty)] {
+        if let Some(table) = table {
+            let (first, last) = lines_of(text, table);
+            stmts.push((first, last, stmt(table.get_ref().to_icon())));
+        }
+    }
+
+    for (name, DesktopTable { label, default, empty }) in &config.desktop {
+        if let Some(label) = label {
+            let (first, last) = lines_of(text, label);
+            stmts.push((first, last, Stmt::Desktop(name.clone(), DesktopSetting::Label(label.get_ref().clone()))));
+        }
+
+        for (table, setting) in [(default, DesktopSetting::Default as fn(Icon) -> DesktopSetting), (empty, DesktopSetting::Empty)] {
+            if let Some(table) = table {
+                let (first, last) = lines_of(text, table);
+                stmts.push((first, last, Stmt::Desktop(name.clone(), setting(table.get_ref().to_icon()))));
+            }
+        }
+    }
+
+    let mut parser = Parser::new();
+    for rule in &config.rule {
+        let (first, last) = lines_of(text, rule);
+        match rule.get_ref().to_stmt(&mut parser) {
+            Ok(stmt) => stmts.push((first, last, stmt)),
+            Err((message, needle)) => errors.push(ParseError::at(text, first, last, needle.as_deref(), message))
+        }
+    }
+
+    (stmts, errors)
+}
+
+// everything in the .i format that has a place in toml, which is everything besides comments, variables and includes
+pub fn to_toml(stmts: &[Stmt]) -> String {
+    let mut config = ConfigFile::default();
+    let mut format = FormatTable::default();
+
+    for stmt in stmts {
+        match stmt {
+            Stmt::FmtBefore(f) => format.before = Some(f.clone()),
+            Stmt::Fmt(f) => format.fmt = Some(f.clone()),
+            Stmt::FmtAfter(f) => format.after = Some(f.clone()),
+            Stmt::IconBefore(f) => format.before_icon = Some(f.clone()),
+            Stmt::IconAfter(f) => format.after_icon = Some(f.clone()),
+            Stmt::Default(i) => config.default = Some(unspanned(IconTable::from_icon(i))),
+            Stmt::Empty(i) => config.empty = Some(unspanned(IconTable::from_icon(i))),
+            Stmt::Desktop(name, setting) => {
+                let desktop = config.desktop.entry(name.clone()).or_default();
+                match setting {
+                    DesktopSetting::Label(l) => desktop.label = Some(unspanned(l.clone())),
+                    DesktopSetting::Default(i) => desktop.default = Some(unspanned(IconTable::from_icon(i))),
+                    DesktopSetting::Empty(i) => desktop.empty = Some(unspanned(IconTable::from_icon(i)))
+                }
+            }
+            Stmt::None | Stmt::Include(_) => (),
+            _ => config.rule.extend(RuleTable::from_stmt(stmt).map(unspanned))
+        }
+    }
+
+    let FormatTable { before, fmt, after, before_icon, after_icon } = &format;
+    if [before, fmt, after, before_icon, after_icon].iter().any(|f| f.is_some()) {
+        config.format = Some(unspanned(format));
+    }
+
+    toml::to_string(&config).unwrap()
+}
@@ -1,22 +1,79 @@
 use xcb::x;
 use xcb::XidNew;
-use std::collections::BTreeMap;
-use crate::icons::Icons;
-use strfmt::{strfmt, strfmt_builder};
+use crate::icons::{Icon, Icons};
+use crate::backend::Desktop;
+use crate::process::{read_process, want_children, PROC_ROOT};
+use std::path::Path;
+use strfmt::strfmt;
+use std::collections::HashMap;
 
 xcb::atoms_struct! {
     #[derive(Copy, Clone, Debug)]
     pub struct Atoms {
         pub wm_name => b"_NET_WM_NAME" only_if_exists = false,
+        pub utf8_string => b"UTF8_STRING" only_if_exists = false,
+        pub number_of_desktops => b"_NET_NUMBER_OF_DESKTOPS" only_if_exists = false,
+        pub desktop_names => b"_NET_DESKTOP_NAMES" only_if_exists = false,
+        pub current_desktop => b"_NET_CURRENT_DESKTOP" only_if_exists = false,
+        pub client_list => b"_NET_CLIENT_LIST" only_if_exists = false,
+        pub active_window => b"_NET_ACTIVE_WINDOW" only_if_exists = false,
+        pub wm_desktop => b"_NET_WM_DESKTOP" only_if_exists = false,
+        pub wm_window_role => b"WM_WINDOW_ROLE" only_if_exists = false,
+        pub wm_window_type => b"_NET_WM_WINDOW_TYPE" only_if_exists = false,
+        pub wm_pid => b"_NET_WM_PID" only_if_exists = false,
     }
 }
 
-#[derive(Debug)]
+impl Atoms {
+    // properties that can change what icon a window gets
+    pub fn is_window_property(&self, atom: x::Atom) -> bool {
+        atom == self.wm_name || atom == x::ATOM_WM_NAME || atom == x::ATOM_WM_CLASS || atom == x::ATOM_WM_HINTS
+            || atom == self.wm_window_role || atom == self.wm_window_type
+    }
+}
+
+// a window id the way xprop, xdotool and bspc print them, hex with 0x or plain decimal
+pub fn parse_window_id(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+// asks X to tell us about property changes, used for the root window and every window we show an icon for
+pub fn watch_window(x_conn: &xcb::Connection, window_id: u32) {
+    let window = unsafe { x::Window::new(window_id) };
+    x_conn.send_request(&x::ChangeWindowAttributes {
+        window,
+        value_list: &[x::Cw::EventMask(x::EventMask::PROPERTY_CHANGE)]
+    });
+}
+
+pub fn unwatch_window(x_conn: &xcb::Connection, window_id: u32) {
+    let window = unsafe { x::Window::new(window_id) };
+    x_conn.send_request(&x::ChangeWindowAttributes {
+        window,
+        value_list: &[x::Cw::EventMask(x::EventMask::NO_EVENT)]
+    });
+}
+
+#[derive(Debug, Default)]
 pub struct KnownWindow {
+    pub id: u32,
     pub class: String,
-    pub title: String
+    pub instance: String, // the first half of WM_CLASS
+    pub title: String,
+    pub role: String,
+    pub window_type: String, // _NET_WM_WINDOW_TYPE without the prefix, like "dialog" or "normal"
+    pub pid: Option<u32>,
+    pub process: Vec<String>, // comm and cmdline of _NET_WM_PID
+    pub child_processes: Vec<String>, // the same for everything below it
+    pub urgent: bool
 }
 
+// the UrgencyHint bit in the flags word of WM_HINTS
+const URGENCY_HINT: u32 = 1 << 8;
+
 impl KnownWindow {
     pub fn new(x_conn: &xcb::Connection, atoms: &Atoms, window_id: &u32) -> Self {
         let window :x::Window;
@@ -27,7 +84,7 @@ impl KnownWindow {
             property: x::ATOM_WM_CLASS,
             r#type: x::ATOM_STRING,
             long_offset: 0,
-            long_length: 8
+            long_length: 64 // chromium apps have instance names longer than 32 bytes
         });
 
         let cookie_title = x_conn.send_request(&x::GetProperty {
@@ -39,71 +96,209 @@ impl KnownWindow {
             long_length: 32
         });
 
-        let class = match x_conn.wait_for_reply(cookie_class) {
-            Ok(r) => std::str::from_utf8(r.value()).expect("The WM_CLASS property is not valid UTF-8").split("\0").nth(1).unwrap_or("sus").to_string(),
-            Err(_) => "who knows?".to_string()
+        let cookie_hints = x_conn.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property: x::ATOM_WM_HINTS,
+            r#type: x::ATOM_WM_HINTS,
+            long_offset: 0,
+            long_length: 1
+        });
+
+        let cookie_role = x_conn.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property: atoms.wm_window_role,
+            r#type: x::ATOM_STRING,
+            long_offset: 0,
+            long_length: 32
+        });
+
+        let cookie_type = x_conn.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property: atoms.wm_window_type,
+            r#type: x::ATOM_ATOM,
+            long_offset: 0,
+            long_length: 1 // the first type is the one the program prefers
+        });
+
+        let cookie_pid = x_conn.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property: atoms.wm_pid,
+            r#type: x::ATOM_CARDINAL,
+            long_offset: 0,
+            long_length: 1
+        });
+
+        let (instance, class) = match x_conn.wait_for_reply(cookie_class) {
+            Ok(r) => {
+                // STRING is latin-1, anything outside ascii is rare enough that a replacement character will do
+                let wm_class = String::from_utf8_lossy(r.value());
+                let mut parts = wm_class.split("\0");
+                (parts.next().unwrap_or_default().to_string(), parts.next().unwrap_or("sus").to_string())
+            }
+            Err(_) => (String::new(), "who knows?".to_string())
         };
 
-        let title = match x_conn.wait_for_reply(cookie_title) {
-            Ok(r) => { std::str::from_utf8(r.value()).expect("The _NET_WM_NAME property is not valid UTF-8").to_string()},
+        let role = match x_conn.wait_for_reply(cookie_role) {
+            Ok(r) => String::from_utf8_lossy(r.value()).to_string(),
+            Err(_) => String::new()
+        };
+
+        let type_atom = match x_conn.wait_for_reply(cookie_type) {
+            Ok(r) if r.format() == 32 => r.value::<x::Atom>().first().copied(),
+            _ => None
+        };
+
+        let window_type = match type_atom.map(|atom| x_conn.send_request(&x::GetAtomName { atom })) {
+            Some(cookie) => match x_conn.wait_for_reply(cookie) {
+                Ok(r) => r.name().to_string().trim_start_matches("_NET_WM_WINDOW_TYPE_").to_lowercase(),
+                Err(_) => String::new()
+            },
+            None => String::new()
+        };
+
+        let mut title = match x_conn.wait_for_reply(cookie_title) {
+            // only the first 128 bytes are asked for, which can cut a character in half
+            Ok(r) => String::from_utf8_lossy(r.value()).to_string(),
             Err(_) => "who knows?".to_string()
         };
 
-        Self { class, title }
+        let pid = match x_conn.wait_for_reply(cookie_pid) {
+            Ok(r) if r.format() == 32 => r.value::<u32>().first().copied(),
+            _ => None
+        };
+
+        let (process, child_processes) = match pid {
+            Some(pid) => read_process(Path::new(PROC_ROOT), pid, want_children()),
+            None => (vec![], vec![])
+        };
+
+        let urgent = match x_conn.wait_for_reply(cookie_hints) {
+            Ok(r) if r.format() == 32 => r.value::<u32>().first().is_some_and(|flags| flags & URGENCY_HINT != 0),
+            _ => false
+        };
+
+        // some older programs only ever set WM_NAME
+        if title.is_empty() {
+            let cookie_name = x_conn.send_request(&x::GetProperty {
+                delete: false,
+                window,
+                property: x::ATOM_WM_NAME,
+                r#type: x::ATOM_ANY,
+                long_offset: 0,
+                long_length: 32
+            });
+
+            if let Ok(r) = x_conn.wait_for_reply(cookie_name) {
+                title = String::from_utf8_lossy(r.value()).to_string();
+            }
+        }
+
+        Self { id: *window_id, class, instance, title, role, window_type, pid, process, child_processes, urgent }
     }
 
-    pub fn new_known(class :String, title: String) -> Self {
-        Self { class, title }
+    pub fn new_known(id: u32, class :String, title: String) -> Self {
+        Self { id, class, title, ..Default::default() }
     }
 }
 
 
-pub fn print_icons(windows: &BTreeMap<String, Option<KnownWindow>>, focused :&str, icons: &Icons) {
-    let mut string :String = icons.get_before().to_string();
+// everything render_icons hands to strfmt besides named regex groups, anything else in fmt is a typo
+pub const FMT_KEYS: &[&str] = &["desktop", "icon", "icons", "focused", "occupied", "color", "window_class", "reversed", "urgent"];
 
-    for (desktop, window) in windows {
-        let icon = match window {
-            Some(w) => {
-                match icons.get_icon(&w.class, &w.title) {
-                    Some(t) => t,
-                    None => icons.get_default()
-                }
-            }
+pub fn window_icon<'a>(window: &KnownWindow, desktop: &str, icons: &'a Icons) -> &'a Icon {
+    match icons.get_icon(window) {
+        Some(t) => t,
+        None => icons.get_default_for(desktop)
+    }
+}
 
-            None => icons.get_empty()
-        };
+// the icon shown for the whole desktop, from the most recently focused window
+pub fn desktop_icon<'a>(desktop: &Desktop, icons: &'a Icons) -> &'a Icon {
+    match desktop.windows.first() {
+        Some(w) => window_icon(w, &desktop.name, icons),
+        None => icons.get_empty_for(&desktop.name)
+    }
+}
 
-        //bc the crate is cool but also kinda bad
-        let reversed_str = icon.reversed_class().to_string();
-        let mut color = match &icon.color {
-            Some(c) => c.to_string(),
-            None => icons.get_default().color.as_ref().unwrap().to_string(),
-        };
+// what a default without colors of its own falls back to, same as the built in default
+const DEFAULT_COLOR: &str = "#000";
 
-        let mut fcolor = match &icon.fcolor {
-            Some(c) => c.to_string(),
-            None => icons.get_default().fcolor.as_ref().unwrap().to_string(),
-        };
+// (color, focused color), falling back to the default icon's
+pub fn icon_colors(icon: &Icon, icons: &Icons) -> (String, String) {
+    let default = icons.get_default();
+    let color = icon.color.as_ref().or(default.color.as_ref()).map_or(DEFAULT_COLOR, |c| c.as_str());
+    let fcolor = icon.fcolor.as_ref().or(default.fcolor.as_ref()).map_or(DEFAULT_COLOR, |c| c.as_str());
 
-        let class = match window {
-            Some(w) => w.class.clone(),
-            None => "".to_string()
-        };
+    (color.to_string(), fcolor.to_string())
+}
 
-        if icon.reversed_class() == 'r' {
-            std::mem::swap(&mut color, &mut fcolor);
-        }
+// icon_colors for a whole desktop, an urgent window that isn't in view gets the urgent color if there is one
+pub fn desktop_colors(desktop: &Desktop, focused: bool, icons: &Icons) -> (String, String) {
+    let icon = desktop_icon(desktop, icons);
+    let (color, fcolor) = icon_colors(icon, icons);
 
-        string += &format!("{}", strfmt!(icons.get_fmt(),
-                desktop => desktop.to_string(),
-                icon => icon.to_string(),
-                focused => if *desktop == focused { "focused" } else { "" },
-                occupied => if window.is_some() { "occupied" } else { "" },
-                color => if *desktop == focused { fcolor } else { color },
-                window_class => class,
-                reversed => reversed_str).unwrap());
+    if focused || !desktop.is_urgent() { return (color, fcolor); }
+
+    match icon.ucolor.as_ref().or(icons.get_default().ucolor.as_ref()) {
+        Some(ucolor) => (ucolor.to_string(), fcolor),
+        None => (color, fcolor)
     }
+}
+
+// one desktop worth of fmt
+pub fn render_desktop(d: &Desktop, focused: bool, icons: &Icons) -> String {
+    let Desktop { name: desktop, windows: desktop_windows, .. } = d;
+
+    // the first window is the most recently focused one
+    let window = desktop_windows.first();
+    let icon = desktop_icon(d, icons);
+
+    let all_icons :String = desktop_windows.iter()
+        .map(|w| format!("{}{}{}", icons.get_before_icon(), window_icon(w, desktop, icons).text(icons.get_use_fallback()), icons.get_after_icon()))
+        .collect();
+
+    let reversed_str = icon.reversed_class().to_string();
+    let (mut color, mut fcolor) = desktop_colors(d, focused, icons);
+
+    let class = match window {
+        Some(w) => w.class.clone(),
+        None => "".to_string()
+    };
 
-    println!("{string}{}", icons.get_after());
+    if icon.reversed_class() == 'r' {
+        std::mem::swap(&mut color, &mut fcolor);
+    }
+
+    // capture groups go in first so they can't shadow the built in keys
+    let mut vars :HashMap<String, String> = icons.capture_names().into_iter().map(|n| (n.to_string(), String::new())).collect();
+    if let Some(w) = window { vars.extend(icons.get_captures(w)); }
+
+    vars.extend([
+        ("desktop", icons.get_label(desktop).to_string()),
+        ("icon", icon.text(icons.get_use_fallback()).to_string()),
+        ("icons", all_icons),
+        ("focused", if focused { "focused" } else { "" }.to_string()),
+        ("occupied", if window.is_some() { "occupied" } else { "" }.to_string()),
+        ("color", if focused { fcolor } else { color }),
+        ("window_class", class),
+        ("reversed", reversed_str),
+        ("urgent", if d.is_urgent() { "urgent" } else { "" }.to_string())
+    ].map(|(k, v)| (k.to_string(), v)));
+
+    // parse_config already turns away unknown placeholders, this is for whatever else strfmt doesn't like
+    strfmt(icons.get_fmt(), &vars).unwrap_or_else(|e| format!("fmt error: {e}"))
 }
 
+pub fn render_icons(desktops: &[&Desktop], focused: Option<u32>, icons: &Icons) -> String {
+    let mut string :String = icons.get_before().to_string();
+
+    for desktop in desktops {
+        string += &render_desktop(desktop, Some(desktop.id) == focused, icons);
+    }
+
+    string + icons.get_after()
+}